@@ -0,0 +1,185 @@
+extern crate rust_game;
+#[macro_use]
+extern crate serde_json;
+
+
+use std::collections::BTreeMap;
+use std::env;
+use std::path::Path;
+use std::time::Instant;
+
+use serde_json::Value;
+
+use rust_game::game_state::{GameState, ScheduledEvent, TEXT_SAVE_EXTENSION};
+use rust_game::save_slots::write_atomically;
+use rust_game::cli::{parse_number_option, file_option, load_unit_definitions};
+
+
+const USAGE: &str = "Usage: oskurts-headless [--ticks N] [--seed SEED] [--script EVENT_FILE]
+                        [--hash-interval TICKS] [--units UNIT_FILE] [--out FILE] [--json]
+                        [save file or scenario]
+
+Runs the simulation without a window as fast as possible and prints a summary.
+Without a save file a new game is generated (from --seed if given).
+
+  --ticks N              Ticks to simulate (default 1000)
+  --seed SEED            Seed of the generated game
+  --script EVENT_FILE    Schedule the events of a JSON event script
+  --hash-interval TICKS  Print the state hash every TICKS ticks
+  --units UNIT_FILE      Unit definitions to use instead of units.json
+  --out FILE             Write the final state, as text if FILE ends in .json
+  --json                 Print the summary as JSON";
+
+const DEFAULT_TICKS: u32 = 1000;
+
+
+struct TeamSummary {
+    units: u32,
+    total_hp: i32,
+    kills: u32,
+}
+
+fn team_summaries(game_state: &GameState) -> BTreeMap<u32, TeamSummary> {
+    let mut teams: BTreeMap<u32, TeamSummary> = BTreeMap::new();
+    for entity in game_state.entity_holder().entities_iter() {
+        let team = teams.entry(entity.team_id()).or_insert(TeamSummary { units: 0, total_hp: 0, kills: 0 });
+        team.units += 1;
+        team.total_hp += entity.hp();
+        team.kills += entity.kills();
+    }
+    teams
+}
+
+fn summary_json(game_state: &GameState, ticks: u32, seconds: f64) -> Value {
+    let teams: BTreeMap<String, Value> = team_summaries(game_state).iter()
+        .map(|(team_id, team)| {
+            (team_id.to_string(), json!({"units": team.units, "total_hp": team.total_hp, "kills": team.kills}))
+        })
+        .collect();
+
+    json!({
+        "ticks_simulated": ticks,
+        "seconds": seconds,
+        "tick": game_state.tick(),
+        "seed": game_state.seed(),
+        "state_hash": format!("{:016x}", game_state.state_hash()),
+        "teams": teams,
+        "buildings": game_state.entity_holder().buildings.len(),
+        "projectiles": game_state.entity_holder().projectiles.len(),
+    })
+}
+
+fn print_summary(game_state: &GameState, ticks: u32, seconds: f64) {
+    println!(
+        "Simulated {} ticks in {:.3} s ({:.0} ticks/s)",
+        ticks,
+        seconds,
+        if seconds > 0.0 { ticks as f64 / seconds } else { 0.0 },
+    );
+    println!("Tick: {}", game_state.tick());
+    println!("Seed: {}", game_state.seed());
+    println!("State hash: {:016x}", game_state.state_hash());
+    for (team_id, team) in team_summaries(game_state).iter() {
+        println!(
+            "Team {}: {} units, {} hp in total, {} kills by living units",
+            team_id, team.units, team.total_hp, team.kills,
+        );
+    }
+    println!("Buildings: {}", game_state.entity_holder().buildings.len());
+    println!("Projectiles: {}", game_state.entity_holder().projectiles.len());
+}
+
+fn load_game_state(file_name: &str) -> Result<GameState, String> {
+    // Unlike GameState::from_file_name this prints nothing, keeping --json output clean
+    if file_name.ends_with(TEXT_SAVE_EXTENSION) {
+        let text = std::fs::read_to_string(file_name).map_err(|e| e.to_string())?;
+        GameState::from_json(&text).map_err(|e| e.to_string())
+    } else {
+        GameState::load_save_file(file_name)
+            .map(|(_header, game_state)| game_state)
+            .map_err(|e| e.to_string())
+    }
+}
+
+fn write_game_state(file_name: &str, game_state: &GameState) -> std::io::Result<()> {
+    let data = if file_name.ends_with(TEXT_SAVE_EXTENSION) {
+        game_state.to_json().into_bytes()
+    } else {
+        game_state.as_save_binary()
+    };
+    write_atomically(Path::new(file_name), &data)
+}
+
+
+pub fn main() -> Result<(), String> {
+    let mut ticks = DEFAULT_TICKS;
+    let mut seed: Option<u64> = None;
+    let mut script_file: Option<String> = None;
+    let mut hash_interval: u32 = 0;
+    let mut unit_file: Option<String> = None;
+    let mut out_file: Option<String> = None;
+    let mut json_output = false;
+    let mut file_name: Option<String> = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(argument) = args.next() {
+        match argument.as_str() {
+            "--ticks" => ticks = parse_number_option(&argument, args.next())?,
+            "--seed" => seed = Some(parse_number_option(&argument, args.next())?),
+            "--script" => script_file = Some(file_option(&argument, args.next())?),
+            "--hash-interval" => hash_interval = parse_number_option(&argument, args.next())?,
+            "--units" => unit_file = Some(file_option(&argument, args.next())?),
+            "--out" => out_file = Some(file_option(&argument, args.next())?),
+            "--json" => json_output = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            },
+            _ if file_name.is_none() && !argument.starts_with("--") => file_name = Some(argument),
+            _ => return Err(format!("Unexpected argument {}\n{}", argument, USAGE)),
+        }
+    }
+
+    load_unit_definitions(unit_file)?;
+
+    let mut game_state = match (file_name, seed) {
+        (Some(_), Some(_)) => return Err("--seed can not be used when loading a game".to_string()),
+        (Some(file_name), None) => load_game_state(&file_name)
+            .map_err(|e| format!("Loading {} failed: {}", file_name, e))?,
+        (None, Some(seed)) => GameState::new_with_seed(seed),
+        (None, None) => GameState::new(),
+    };
+    game_state.debug_state_hash_interval = hash_interval;
+
+    if let Some(file_name) = script_file {
+        let text = std::fs::read_to_string(&file_name)
+            .map_err(|e| format!("Reading event script {} failed: {}", file_name, e))?;
+        let scheduled_events = ScheduledEvent::list_from_json(&text)
+            .map_err(|e| format!("Loading event script failed: {}", e))?;
+        for scheduled_event in scheduled_events {
+            game_state.schedule_event(scheduled_event.tick, scheduled_event.game_event);
+        }
+    }
+
+    let start_time = Instant::now();
+    for _ in 0..ticks {
+        game_state.do_tick();
+    }
+    let elapsed = start_time.elapsed();
+    let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1_000_000_000.0;
+
+    if let Some(out_file) = out_file {
+        write_game_state(&out_file, &game_state)
+            .map_err(|e| format!("Writing {} failed: {}", out_file, e))?;
+    }
+
+    if json_output {
+        let output = serde_json::to_string_pretty(&summary_json(&game_state, ticks, seconds))
+            .map_err(|e| e.to_string())?;
+        println!("{}", output);
+    } else {
+        print_summary(&game_state, ticks, seconds);
+    }
+
+    Ok(())
+}
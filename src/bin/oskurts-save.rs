@@ -0,0 +1,203 @@
+extern crate rust_game;
+#[macro_use]
+extern crate serde_json;
+
+
+use std::collections::BTreeMap;
+use std::env;
+
+use serde_json::Value;
+
+use rust_game::point::Point;
+use rust_game::map::SecondLevelType;
+use rust_game::entity::{Entity, Task};
+use rust_game::game_state::{GameState, SaveHeader};
+use rust_game::cli::{file_option, load_unit_definitions};
+
+
+const USAGE: &str = "Usage: oskurts-save [--json | --export] [--no-map] [--units UNIT_FILE] <save file>
+
+  --json              Print the save header and the game state in the text
+                      format as JSON for scripting
+  --export            Print the save in the text format that the game can load
+                      (write it to a .json file and pass that to the game)
+  --no-map            Leave out the map view
+  --units UNIT_FILE   Unit definitions the game ran with instead of units.json";
+
+fn print_map(game_state: &GameState) {
+    // Units are drawn as the last digit of their team id on top of the map
+    let map = game_state.map();
+    let mut rows: Vec<Vec<char>> = (0..map.height() as i32).map(|y| {
+        (0..map.width() as i32).map(|x| {
+            match map.get_at_second_level(x, y) {
+                SecondLevelType::Empty => map.get_at(x, y).as_char(),
+                second_level_type => second_level_type.as_char(),
+            }
+        }).collect()
+    }).collect();
+
+    for entity in game_state.entity_holder().entities_iter() {
+        let (x, y) = entity.location().as_i();
+        if x >= 0 && y >= 0 && x < map.width() as i32 && y < map.height() as i32 {
+            rows[y as usize][x as usize] = std::char::from_digit(entity.team_id() % 10, 10).unwrap();
+        }
+    }
+
+    println!("+{}+", "-".repeat(map.width() as usize));
+    for row in rows.iter() {
+        println!("|{}|", row.iter().collect::<String>());
+    }
+    println!("+{}+", "-".repeat(map.width() as usize));
+    println!("Legend: '.' grass, '~' water, ':' sand, '#' rock, 'X' empty, 'B' building, 't' tree, ',' cut tree, 0-9 unit of team");
+}
+
+fn format_point(point: &Point) -> String {
+    format!("({:.2}, {:.2})", point.x, point.y)
+}
+
+fn format_task(task: &Task) -> String {
+    match task {
+        Task::Idle => "Idle".to_string(),
+        Task::Move { point } => format!("Move {}", format_point(point)),
+        Task::AttackMove { point } => format!("AttackMove {}", format_point(point)),
+        Task::Gather { point, resource_type } =>
+            format!("Gather {:?} {}", resource_type, format_point(point)),
+    }
+}
+
+fn entities_by_team(game_state: &GameState) -> BTreeMap<u32, Vec<&Entity>> {
+    let mut teams: BTreeMap<u32, Vec<&Entity>> = BTreeMap::new();
+    for entity in game_state.entity_holder().entities_iter() {
+        teams.entry(entity.team_id()).or_insert_with(Vec::new).push(entity);
+    }
+    for entities in teams.values_mut() {
+        entities.sort_by_key(|entity| entity.id());
+    }
+    teams
+}
+
+fn print_listing(header: &SaveHeader, game_state: &GameState) {
+    println!(
+        "Format version {}, engine version {}, created at {}",
+        header.format_version,
+        header.engine_version,
+        header.created_at,
+    );
+    println!("Tick: {}", game_state.tick());
+    println!("Seed: {}", game_state.seed());
+    println!("State hash: {:016x}", game_state.state_hash());
+    println!("Map: {}x{}", game_state.map().width(), game_state.map().height());
+    println!("Next entity id: {}", game_state.entity_holder().id_counter);
+
+    for (team_id, entities) in entities_by_team(game_state).iter() {
+        println!();
+        println!("Team {}: {} units", team_id, entities.len());
+        for entity in entities.iter() {
+            println!(
+                "  #{} {:?} at {} hp {}/{} cooldown {} kills {} task {} path {} waypoints{}",
+                entity.id(),
+                entity.entity_type(),
+                format_point(entity.location()),
+                entity.hp(),
+                entity.max_hp(),
+                entity.cooldown(),
+                entity.kills(),
+                format_task(entity.task()),
+                entity.path().len(),
+                match entity.closest_seen_enemy_id() {
+                    Some(enemy_id) => format!(" enemy #{}", enemy_id),
+                    None => "".to_string(),
+                },
+            );
+        }
+    }
+
+    // Buildings do not store a team in the save format
+    let buildings = &game_state.entity_holder().buildings;
+    println!();
+    println!("Buildings: {}", buildings.len());
+    for building in buildings.iter() {
+        println!(
+            "  at ({}, {}) size {}x{}",
+            building.x(), building.y(), building.width(), building.height(),
+        );
+    }
+
+    let projectiles = &game_state.entity_holder().projectiles;
+    println!();
+    println!("Projectiles: {}", projectiles.len());
+    for projectile in projectiles.iter() {
+        println!(
+            "  {:?} of team {} at {} towards {} damage {}{}{}",
+            projectile.projectile_type(),
+            projectile.team_id(),
+            format_point(projectile.location()),
+            format_point(projectile.end_point()),
+            projectile.damage(),
+            match projectile.shooter_id() {
+                Some(shooter_id) => format!(" shot by #{}", shooter_id),
+                None => "".to_string(),
+            },
+            match projectile.target_id() {
+                Some(target_id) => format!(" at #{}", target_id),
+                None => "".to_string(),
+            },
+        );
+    }
+}
+
+fn save_json(header: &SaveHeader, game_state: &GameState) -> Value {
+    // The game state is in the text format, so it can be loaded with GameState::from_json
+    json!({
+        "header": header,
+        "state_hash": format!("{:016x}", game_state.state_hash()),
+        "game_state": game_state,
+    })
+}
+
+
+pub fn main() -> Result<(), String> {
+    let mut json_output = false;
+    let mut text_export = false;
+    let mut show_map = true;
+    let mut unit_file: Option<String> = None;
+    let mut file_name: Option<String> = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(argument) = args.next() {
+        match argument.as_str() {
+            "--json" => json_output = true,
+            "--export" => text_export = true,
+            "--no-map" => show_map = false,
+            "--units" => unit_file = Some(file_option(&argument, args.next())?),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            },
+            _ if file_name.is_none() && !argument.starts_with("--") => file_name = Some(argument),
+            _ => return Err(format!("Unexpected argument {}\n{}", argument, USAGE)),
+        }
+    }
+    let file_name = file_name.ok_or(USAGE.to_string())?;
+
+    // Max hp in the listings comes from the unit definitions
+    load_unit_definitions(unit_file)?;
+
+    let (header, game_state) = GameState::load_save_file(&file_name)
+        .map_err(|e| format!("Loading {} failed: {}", file_name, e))?;
+
+    if text_export {
+        println!("{}", game_state.to_json());
+    } else if json_output {
+        let output = serde_json::to_string_pretty(&save_json(&header, &game_state))
+            .map_err(|e| e.to_string())?;
+        println!("{}", output);
+    } else {
+        if show_map {
+            print_map(&game_state);
+        }
+        print_listing(&header, &game_state);
+    }
+
+    Ok(())
+}
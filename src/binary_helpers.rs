@@ -1,117 +1,465 @@
 use std::iter::Iterator;
+use std::fmt;
 
 use byteorder::{ByteOrder, LittleEndian, BigEndian};
+use crc32fast;
 
 
-pub trait Binaryable {
-    fn as_binary(&self) -> Vec<u8>;
-    fn from_binary(binary_data: Vec<u8>) -> Self;
+// Version of the binary layout written by BinaryWriter. Bump this whenever
+// the layout of any record changes and make the record's read_binary branch
+// on BinaryReader::format_version so older saves keep loading.
+// See SAVE_FORMAT.md for the layout of every record.
+pub const SAVE_FORMAT_VERSION: u32 = 11;
+
+// Format versions before this one stored integers big-endian
+const LITTLE_ENDIAN_INTEGERS_SINCE_VERSION: u32 = 2;
 
-    fn as_padded_binary(&self) -> Vec<u8> {
-        let mut binary_data: Vec<u8> = Vec::new();
-        let original_binary_data = self.as_binary();
 
-        binary_data.extend(u32_as_bytes(original_binary_data.len() as u32));
-        binary_data.extend(original_binary_data);
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    Truncated { needed: usize, available: usize },
+    UnknownTaskTag(u8),
+    UnknownEntityType(u8),
+    UnknownGroundType(u8),
+    UnknownSecondLevelType(u8),
+    UnknownResourceType(u8),
+    UnknownProjectileType(u8),
+    UnknownMapEncoding(u8),
+    UnknownEventTag(u8),
+    MapTooLarge { width: u32, height: u32 },
+    NotAReplayFile,
+    TrailingBytes(usize),
+    LengthMismatch { expected: usize, actual: usize },
+    UnsupportedVersion(u32),
+    ChecksumMismatch { expected: u32, actual: u32 },
+    Io(String),
+    InvalidText(String),
+}
+
 
-        binary_data
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Truncated { needed, available } =>
+                write!(f, "Data truncated, needed {} bytes but only {} available", needed, available),
+            DecodeError::UnknownTaskTag(tag) => write!(f, "Unknown Task tag {}", tag),
+            DecodeError::UnknownEntityType(tag) => write!(f, "Unknown EntityType {}", tag),
+            DecodeError::UnknownGroundType(tag) => write!(f, "Unknown GroundType {}", tag),
+            DecodeError::UnknownSecondLevelType(tag) => write!(f, "Unknown SecondLevelType {}", tag),
+            DecodeError::UnknownResourceType(tag) => write!(f, "Unknown ResourceType {}", tag),
+            DecodeError::UnknownProjectileType(tag) => write!(f, "Unknown ProjectileType {}", tag),
+            DecodeError::UnknownMapEncoding(tag) => write!(f, "Unknown map layer encoding {}", tag),
+            DecodeError::UnknownEventTag(tag) => write!(f, "Unknown GameEvent tag {}", tag),
+            DecodeError::MapTooLarge { width, height } =>
+                write!(f, "Map of {}x{} tiles is too large", width, height),
+            DecodeError::NotAReplayFile => write!(f, "Not a replay file"),
+            DecodeError::TrailingBytes(amount) => write!(f, "{} unexpected trailing bytes", amount),
+            DecodeError::LengthMismatch { expected, actual } =>
+                write!(f, "Length mismatch, expected {} bytes but got {}", expected, actual),
+            DecodeError::UnsupportedVersion(version) =>
+                write!(f, "Unsupported save format version {}", version),
+            DecodeError::ChecksumMismatch { expected, actual } =>
+                write!(f, "Checksum mismatch, expected {:08x} but data has {:08x}", expected, actual),
+            DecodeError::Io(message) => write!(f, "IO error: {}", message),
+            DecodeError::InvalidText(message) => write!(f, "Invalid text save: {}", message),
+        }
     }
 }
 
 
-pub fn iter_as_bytes<'a, I: Iterator<Item = &'a T>, T: Binaryable + 'a>(data: I) -> Vec<u8> {
-    /*
-    iter_as_bytes(data.iter())
+// 64-bit FNV-1a. Used instead of std's DefaultHasher, whose output may change
+// between Rust releases, so hashes can be compared across builds and machines.
+pub fn stable_hash(binary_data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in binary_data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
 }
-pub fn iter_as_bytes<T: Binaryable>(iter: Iterator<Item=&T>) -> Vec<u8>
-{
-    */
-    let mut binary_data: Vec<u8> = Vec::new();
 
-    let mut vector_binary_data: Vec<u8> = Vec::new();
-    // for entity in data.iter() {
-    for entity in data {
-        vector_binary_data.extend(entity.as_padded_binary());
+
+pub trait Binaryable {
+    fn write_binary(&self, writer: &mut BinaryWriter);
+    fn read_binary(reader: &mut BinaryReader) -> Result<Self, DecodeError> where Self: Sized;
+
+    fn as_binary(&self) -> Vec<u8> {
+        let mut writer = BinaryWriter::new();
+        self.write_binary(&mut writer);
+        writer.into_binary()
     }
-    binary_data.extend(u32_as_bytes(vector_binary_data.len() as u32));
-    binary_data.extend(vector_binary_data);
 
-    binary_data
+    fn from_binary(binary_data: &[u8]) -> Result<Self, DecodeError> where Self: Sized {
+        let mut reader = BinaryReader::new(binary_data);
+        let value = Self::read_binary(&mut reader)?;
+        reader.expect_empty()?;
+        Ok(value)
+    }
 }
 
 
-pub fn u32_as_bytes(input: u32) -> Vec<u8> {
-    let mut binary_data: Vec<u8> = vec![0; 4];
+// Cursor over borrowed binary data. Reading never copies the underlying
+// bytes, so decoding is linear in the size of the data. The reader knows the
+// format version of the data so records written by older versions can be
+// upgraded while they are read.
+pub struct BinaryReader<'a> {
+    binary_data: &'a [u8],
+    position: usize,
+    format_version: u32,
+}
+
 
-    binary_data[3] = (input >> 0) as u8;
-    binary_data[2] = (input >> 8) as u8;
-    binary_data[1] = (input >> 16) as u8;
-    binary_data[0] = (input >> 24) as u8;
+impl<'a> BinaryReader<'a> {
+    pub fn new(binary_data: &'a [u8]) -> BinaryReader<'a> {
+        BinaryReader {
+            binary_data: binary_data,
+            position: 0,
+            format_version: SAVE_FORMAT_VERSION,
+        }
+    }
 
-    binary_data
-}
+    pub fn for_format_version(binary_data: &'a [u8], format_version: u32) -> Result<BinaryReader<'a>, DecodeError> {
+        let mut reader = BinaryReader::new(binary_data);
+        reader.set_format_version(format_version)?;
+        Ok(reader)
+    }
 
+    pub fn format_version(&self) -> u32 {
+        self.format_version
+    }
 
-pub fn i32_as_bytes(input: i32) -> Vec<u8> {
-    let mut binary_data: Vec<u8> = vec![0; 4];
+    pub fn set_format_version(&mut self, format_version: u32) -> Result<(), DecodeError> {
+        if format_version > SAVE_FORMAT_VERSION {
+            return Err(DecodeError::UnsupportedVersion(format_version));
+        }
+        self.format_version = format_version;
+        Ok(())
+    }
 
-    binary_data[3] = (input >> 0) as u8;
-    binary_data[2] = (input >> 8) as u8;
-    binary_data[1] = (input >> 16) as u8;
-    binary_data[0] = (input >> 24) as u8;
+    fn big_endian_integers(&self) -> bool {
+        self.format_version < LITTLE_ENDIAN_INTEGERS_SINCE_VERSION
+    }
 
-    binary_data
-}
+    pub fn remaining(&self) -> usize {
+        self.binary_data.len() - self.position
+    }
 
-pub fn f32_as_bytes(input: f32) -> Vec<u8> {
-    let mut binary_data: Vec<u8> = vec![0; 4];
-    let raw_bytes: [u8; 4] = unsafe { std::mem::transmute(input) };
-    raw_bytes.to_vec()
-}
+    pub fn is_empty(&self) -> bool {
+        self.remaining() == 0
+    }
 
-pub fn pop_bytes_from_vec(binary_data: Vec<u8>, amount: u32) -> (Vec<u8>, Vec<u8>) {
-    let (a, b) = binary_data.split_at(amount as usize);
-    (
-        a.to_vec(),
-        b.to_vec(),
-    )
-}
+    pub fn peek_bytes(&self) -> &'a [u8] {
+        // Rest of the data without advancing the cursor
+        &self.binary_data[self.position..]
+    }
 
-pub fn pop_f32(binary_data: Vec<u8>) -> (f32, Vec<u8>) {
-    let (value_data, binary_data) = pop_bytes_from_vec(binary_data, 4);
-    (
-        LittleEndian::read_f32(value_data.as_slice()),
-        binary_data,
-    )
-}
+    pub fn verify_trailing_checksum(&mut self) -> Result<(), DecodeError> {
+        // Checks the CRC-32 written by BinaryWriter::write_checksum at the end
+        // of the data and stops the reader before it. The checksum covers all
+        // data of the reader, including the bytes that were already read.
+        if self.remaining() < 4 {
+            return Err(DecodeError::Truncated { needed: 4, available: self.remaining() });
+        }
+        let checksum_position = self.binary_data.len() - 4;
+        let expected = LittleEndian::read_u32(&self.binary_data[checksum_position..]);
+        let actual = crc32fast::hash(&self.binary_data[..checksum_position]);
+        if expected != actual {
+            return Err(DecodeError::ChecksumMismatch { expected: expected, actual: actual });
+        }
+        self.binary_data = &self.binary_data[..checksum_position];
+        Ok(())
+    }
+
+    pub fn expect_empty(&self) -> Result<(), DecodeError> {
+        if !self.is_empty() {
+            return Err(DecodeError::TrailingBytes(self.remaining()));
+        }
+        Ok(())
+    }
+
+    pub fn read_bytes(&mut self, amount: usize) -> Result<&'a [u8], DecodeError> {
+        if self.remaining() < amount {
+            return Err(DecodeError::Truncated {
+                needed: amount,
+                available: self.remaining(),
+            });
+        }
+        let bytes = &self.binary_data[self.position..self.position + amount];
+        self.position += amount;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, DecodeError> {
+        let bytes = self.read_bytes(4)?;
+        Ok(if self.big_endian_integers() {BigEndian::read_u32(bytes)} else {LittleEndian::read_u32(bytes)})
+    }
+
+    pub fn read_i32(&mut self) -> Result<i32, DecodeError> {
+        let bytes = self.read_bytes(4)?;
+        Ok(if self.big_endian_integers() {BigEndian::read_i32(bytes)} else {LittleEndian::read_i32(bytes)})
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, DecodeError> {
+        let bytes = self.read_bytes(8)?;
+        Ok(if self.big_endian_integers() {BigEndian::read_u64(bytes)} else {LittleEndian::read_u64(bytes)})
+    }
+
+    pub fn read_f32(&mut self) -> Result<f32, DecodeError> {
+        Ok(LittleEndian::read_f32(self.read_bytes(4)?))
+    }
 
-pub fn pop_i32(binary_data: Vec<u8>) -> (i32, Vec<u8>) {
-    let (value_data, binary_data) = pop_bytes_from_vec(binary_data, 4);
-    (
-        BigEndian::read_i32(value_data.as_slice()),
-        binary_data,
-    )
+    pub fn read_padded_bytes(&mut self) -> Result<&'a [u8], DecodeError> {
+        let pad_amount = self.read_u32()?;
+        self.read_bytes(pad_amount as usize)
+    }
+
+    pub fn read_padded(&mut self) -> Result<BinaryReader<'a>, DecodeError> {
+        let format_version = self.format_version;
+        Ok(BinaryReader {
+            binary_data: self.read_padded_bytes()?,
+            position: 0,
+            format_version: format_version,
+        })
+    }
+
+    pub fn read_record<T: Binaryable>(&mut self) -> Result<T, DecodeError> {
+        let mut record_reader = self.read_padded()?;
+        let record = T::read_binary(&mut record_reader)?;
+        record_reader.expect_empty()?;
+        Ok(record)
+    }
+
+    pub fn read_list<T: Binaryable>(&mut self) -> Result<Vec<T>, DecodeError> {
+        let mut list_reader = self.read_padded()?;
+        let mut records: Vec<T> = Vec::new();
+        while !list_reader.is_empty() {
+            records.push(list_reader.read_record::<T>()?);
+        }
+        Ok(records)
+    }
 }
 
-pub fn pop_u32(binary_data: Vec<u8>) -> (u32, Vec<u8>) {
-    let (value_data, binary_data) = pop_bytes_from_vec(binary_data, 4);
-    (
-        BigEndian::read_u32(value_data.as_slice()),
-        binary_data,
-    )
+
+// Writes data in the current format version. Every integer and float is
+// little-endian.
+pub struct BinaryWriter {
+    binary_data: Vec<u8>,
 }
 
-pub fn pop_u8(binary_data: Vec<u8>) -> (u8, Vec<u8>) {
-    let (value_data, binary_data) = pop_bytes_from_vec(binary_data, 1);
-    (
-        value_data[0],
-        // BigEndian::read_u8(value_data.as_slice()),
-        binary_data,
-    )
+
+impl BinaryWriter {
+    pub fn new() -> BinaryWriter {
+        BinaryWriter {
+            binary_data: Vec::new(),
+        }
+    }
+
+    pub fn into_binary(self) -> Vec<u8> {
+        self.binary_data
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.binary_data.extend_from_slice(bytes);
+    }
+
+    pub fn write_u8(&mut self, input: u8) {
+        self.binary_data.push(input);
+    }
+
+    pub fn write_u32(&mut self, input: u32) {
+        let mut bytes = [0u8; 4];
+        LittleEndian::write_u32(&mut bytes, input);
+        self.write_bytes(&bytes);
+    }
+
+    pub fn write_i32(&mut self, input: i32) {
+        let mut bytes = [0u8; 4];
+        LittleEndian::write_i32(&mut bytes, input);
+        self.write_bytes(&bytes);
+    }
+
+    pub fn write_u64(&mut self, input: u64) {
+        let mut bytes = [0u8; 8];
+        LittleEndian::write_u64(&mut bytes, input);
+        self.write_bytes(&bytes);
+    }
+
+    pub fn write_f32(&mut self, input: f32) {
+        let mut bytes = [0u8; 4];
+        LittleEndian::write_f32(&mut bytes, input);
+        self.write_bytes(&bytes);
+    }
+
+    pub fn write_checksum(&mut self) {
+        // CRC-32 of everything written so far
+        let checksum = crc32fast::hash(&self.binary_data);
+        self.write_u32(checksum);
+    }
+
+    pub fn write_padded<F: FnOnce(&mut BinaryWriter)>(&mut self, write_content: F) {
+        // Reserves room for the length and fills it in once the content is written
+        let length_position = self.binary_data.len();
+        self.write_u32(0);
+        write_content(self);
+        let content_length = (self.binary_data.len() - length_position - 4) as u32;
+        LittleEndian::write_u32(&mut self.binary_data[length_position..length_position + 4], content_length);
+    }
+
+    pub fn write_padded_bytes(&mut self, bytes: &[u8]) {
+        self.write_padded(|writer| writer.write_bytes(bytes));
+    }
+
+    pub fn write_record<T: Binaryable>(&mut self, record: &T) {
+        self.write_padded(|writer| record.write_binary(writer));
+    }
+
+    pub fn write_list<'a, I: Iterator<Item = &'a T>, T: Binaryable + 'a>(&mut self, records: I) {
+        self.write_padded(|writer| {
+            for record in records {
+                writer.write_record(record);
+            }
+        });
+    }
 }
 
-pub fn pop_padded(binary_data: Vec<u8>) -> (Vec<u8>, Vec<u8>) {
-    let (pad_amount, binary_data) = pop_u32(binary_data);
-    pop_bytes_from_vec(binary_data, pad_amount)
+
+impl Default for BinaryWriter {
+    fn default() -> BinaryWriter {
+        BinaryWriter::new()
+    }
 }
 
+
+#[cfg(test)]
+pub mod tests {
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::SmallRng;
+
+    use super::*;
+
+    pub const PROPERTY_TEST_CASES: u64 = 200;
+
+    pub fn property_test_rng(case: u64) -> SmallRng {
+        SmallRng::seed_from_u64(case)
+    }
+
+    pub fn random_f32(rng: &mut SmallRng) -> f32 {
+        // Any bit pattern, including NaNs and infinities
+        f32::from_bits(rng.gen())
+    }
+
+    pub fn assert_round_trip<T: Binaryable>(value: &T) {
+        let binary_data = value.as_binary();
+        let decoded = T::from_binary(&binary_data).unwrap();
+        assert_eq!(decoded.as_binary(), binary_data);
+    }
+
+    #[test]
+    fn writer_uses_little_endian() {
+        let mut writer = BinaryWriter::new();
+        writer.write_u32(0x01020304);
+        writer.write_i32(-2);
+        writer.write_u64(0x0102030405060708);
+        writer.write_f32(1.0);
+        assert_eq!(writer.into_binary(), vec![
+            4, 3, 2, 1,
+            254, 255, 255, 255,
+            8, 7, 6, 5, 4, 3, 2, 1,
+            0, 0, 128, 63,
+        ]);
+    }
+
+    #[test]
+    fn stable_hash_reference_values() {
+        assert_eq!(stable_hash(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(stable_hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(stable_hash(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn legacy_reader_uses_big_endian_integers() {
+        let binary_data = vec![1, 2, 3, 4, 255, 255, 255, 254, 0, 0, 128, 63];
+        let mut reader = BinaryReader::for_format_version(&binary_data, 1).unwrap();
+        assert_eq!(reader.read_u32().unwrap(), 0x01020304);
+        assert_eq!(reader.read_i32().unwrap(), -2);
+        assert_eq!(reader.read_f32().unwrap(), 1.0);
+        reader.expect_empty().unwrap();
+    }
+
+    #[test]
+    fn primitives_round_trip() {
+        for case in 0..PROPERTY_TEST_CASES {
+            let mut rng = property_test_rng(case);
+            let value_u8: u8 = rng.gen();
+            let value_u32: u32 = rng.gen();
+            let value_i32: i32 = rng.gen();
+            let value_u64: u64 = rng.gen();
+            let value_f32 = random_f32(&mut rng);
+            let value_bytes: Vec<u8> = (0..rng.gen_range(0, 20)).map(|_| rng.gen()).collect();
+
+            let mut writer = BinaryWriter::new();
+            writer.write_u8(value_u8);
+            writer.write_u32(value_u32);
+            writer.write_i32(value_i32);
+            writer.write_u64(value_u64);
+            writer.write_f32(value_f32);
+            writer.write_padded_bytes(&value_bytes);
+            let binary_data = writer.into_binary();
+
+            let mut reader = BinaryReader::new(&binary_data);
+            assert_eq!(reader.read_u8().unwrap(), value_u8);
+            assert_eq!(reader.read_u32().unwrap(), value_u32);
+            assert_eq!(reader.read_i32().unwrap(), value_i32);
+            assert_eq!(reader.read_u64().unwrap(), value_u64);
+            assert_eq!(reader.read_f32().unwrap().to_bits(), value_f32.to_bits());
+            assert_eq!(reader.read_padded_bytes().unwrap(), value_bytes.as_slice());
+            reader.expect_empty().unwrap();
+        }
+    }
+
+    #[test]
+    fn reader_reports_truncated_and_trailing_data() {
+        let binary_data = vec![1, 2, 3];
+        let mut reader = BinaryReader::new(&binary_data);
+        assert_eq!(reader.read_u32(), Err(DecodeError::Truncated { needed: 4, available: 3 }));
+        assert_eq!(reader.expect_empty(), Err(DecodeError::TrailingBytes(3)));
+    }
+
+    #[test]
+    fn checksum_detects_corruption() {
+        let mut writer = BinaryWriter::new();
+        writer.write_u32(7);
+        writer.write_padded_bytes(b"save data");
+        writer.write_checksum();
+        let binary_data = writer.into_binary();
+
+        let mut reader = BinaryReader::new(&binary_data);
+        assert_eq!(reader.read_u32().unwrap(), 7);
+        reader.verify_trailing_checksum().unwrap();
+        assert_eq!(reader.read_padded_bytes().unwrap(), b"save data");
+        reader.expect_empty().unwrap();
+
+        for position in 0..binary_data.len() {
+            let mut corrupted = binary_data.clone();
+            corrupted[position] ^= 0x10;
+            match BinaryReader::new(&corrupted).verify_trailing_checksum() {
+                Err(DecodeError::ChecksumMismatch { .. }) => {},
+                result => panic!("Corrupted byte {} not detected: {:?}", position, result),
+            }
+        }
+        assert!(BinaryReader::new(&binary_data[..3]).verify_trailing_checksum().is_err());
+    }
+
+    #[test]
+    fn reader_rejects_newer_format_versions() {
+        assert!(BinaryReader::for_format_version(&[], SAVE_FORMAT_VERSION).is_ok());
+        assert_eq!(
+            BinaryReader::for_format_version(&[], SAVE_FORMAT_VERSION + 1).err(),
+            Some(DecodeError::UnsupportedVersion(SAVE_FORMAT_VERSION + 1)),
+        );
+    }
+}
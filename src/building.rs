@@ -1,25 +1,25 @@
 
-use super::binary_helpers::Binaryable;
-use super::binary_helpers;
+use super::binary_helpers::{Binaryable, BinaryReader, BinaryWriter, DecodeError};
 
+use serde::{Serialize, Deserialize};
 
+
+#[derive(Serialize, Deserialize)]
 pub struct Building {
     x: i32,
     y: i32,
 }
 
 impl Binaryable for Building {
-    fn as_binary(&self) -> Vec<u8> {
-        let mut binary_data: Vec<u8> = Vec::new();
-        binary_data.extend(binary_helpers::i32_as_bytes(self.x));
-        binary_data.extend(binary_helpers::i32_as_bytes(self.y));
-        binary_data
+    fn write_binary(&self, writer: &mut BinaryWriter) {
+        writer.write_i32(self.x);
+        writer.write_i32(self.y);
     }
 
-    fn from_binary(binary_data: Vec<u8>) -> Building{
-        let (x, binary_data) = binary_helpers::pop_i32(binary_data);
-        let (y, binary_data) = binary_helpers::pop_i32(binary_data);
-        Building::new((x, y))
+    fn read_binary(reader: &mut BinaryReader) -> Result<Building, DecodeError> {
+        let x = reader.read_i32()?;
+        let y = reader.read_i32()?;
+        Ok(Building::new((x, y)))
     }
 }
 
@@ -49,4 +49,22 @@ impl Building {
 }
 
 
+#[cfg(test)]
+pub mod tests {
+    use rand::Rng;
+    use rand::rngs::SmallRng;
+
+    use super::*;
+    use super::super::binary_helpers::tests::{assert_round_trip, property_test_rng, PROPERTY_TEST_CASES};
+
+    pub fn random_building(rng: &mut SmallRng) -> Building {
+        Building::new((rng.gen(), rng.gen()))
+    }
 
+    #[test]
+    fn building_round_trip() {
+        for case in 0..PROPERTY_TEST_CASES {
+            assert_round_trip(&random_building(&mut property_test_rng(case)));
+        }
+    }
+}
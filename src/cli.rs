@@ -0,0 +1,40 @@
+use std::str::FromStr;
+
+use super::unit_types;
+
+
+// Command line helpers shared by the game and the tools in src/bin
+
+
+pub fn parse_number_option<T: FromStr>(option: &str, value: Option<String>) -> Result<T, String> {
+    value.and_then(|value| value.parse().ok())
+        .ok_or(format!("{} needs a number", option))
+}
+
+pub fn file_option(option: &str, value: Option<String>) -> Result<String, String> {
+    value.ok_or(format!("{} needs a file", option))
+}
+
+pub fn load_unit_definitions(unit_file: Option<String>) -> Result<(), String> {
+    // The file given with --units, otherwise units.json if it exists. Must be
+    // called before any unit stats are looked up.
+    match unit_file {
+        Some(file_name) => unit_types::load_file(&file_name),
+        None => unit_types::load_default_file(),
+    }.map_err(|e| format!("Loading unit definitions failed: {}", e))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_need_a_value() {
+        assert_eq!(parse_number_option::<u32>("--ticks", Some("12".to_string())), Ok(12));
+        assert_eq!(parse_number_option::<u32>("--ticks", Some("-1".to_string())), Err("--ticks needs a number".to_string()));
+        assert_eq!(parse_number_option::<u64>("--seed", None), Err("--seed needs a number".to_string()));
+        assert_eq!(file_option("--out", Some("a.json".to_string())), Ok("a.json".to_string()));
+        assert_eq!(file_option("--out", None), Err("--out needs a file".to_string()));
+    }
+}
@@ -2,20 +2,35 @@ use super::point;
 use super::map;
 use super::projectile::Projectile;
 
-use super::binary_helpers::{Binaryable, u32_as_bytes, i32_as_bytes, f32_as_bytes};
-use super::binary_helpers;
+use super::binary_helpers::{Binaryable, BinaryReader, BinaryWriter, DecodeError};
+use super::unit_types::{self, UnitStats};
+
+use serde::{Serialize, Deserialize};
+
+
+// Saves before this version wrote the closest seen enemy point flag inverted
+// (0 present, 1 missing) and did not store the closest seen enemy id
+const CLOSEST_SEEN_ENEMY_ID_SINCE_VERSION: u32 = 3;
+
+// Saves before this version did not count kills
+const KILLS_SINCE_VERSION: u32 = 9;
+
+// Saves before this version did not store the target of a melee swing in
+// progress
+const SWING_TARGET_SINCE_VERSION: u32 = 11;
 
 
 // #[derive(Clone)]
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
 pub enum ResourceType {
     Wood,
     Gold,
 }
 
 
-#[derive(Clone)]
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub enum Task {
+    #[default]
     Idle,
 
     Move {point: point::Point},
@@ -26,69 +41,68 @@ pub enum Task {
 
 
 impl Binaryable for Task {
-    fn as_binary(&self) -> Vec<u8> {
-        let mut binary_data: Vec<u8> = Vec::new();
+    fn write_binary(&self, writer: &mut BinaryWriter) {
         match self {
             Task::Idle => {
-                binary_data.push(0u8);
+                writer.write_u8(0u8);
             },
             Task::Move { point } => {
-                binary_data.push(1u8);
-                binary_data.extend(f32_as_bytes(point.x));
-                binary_data.extend(f32_as_bytes(point.y));
+                writer.write_u8(1u8);
+                writer.write_f32(point.x);
+                writer.write_f32(point.y);
             },
             Task::AttackMove { point } => {
-                binary_data.push(2u8);
-                binary_data.extend(f32_as_bytes(point.x));
-                binary_data.extend(f32_as_bytes(point.y));
+                writer.write_u8(2u8);
+                writer.write_f32(point.x);
+                writer.write_f32(point.y);
             },
             Task::Gather { point, resource_type } => {
-                binary_data.push(3u8);
-                binary_data.extend(f32_as_bytes(point.x));
-                binary_data.extend(f32_as_bytes(point.y));
-                binary_data.push(*resource_type as u8);
+                writer.write_u8(3u8);
+                writer.write_f32(point.x);
+                writer.write_f32(point.y);
+                writer.write_u8(*resource_type as u8);
             }
         }
-        binary_data
     }
 
-    fn from_binary(binary_data: Vec<u8>) -> Task {
-        let (task_type, binary_data) = binary_helpers::pop_u8(binary_data);
+    fn read_binary(reader: &mut BinaryReader) -> Result<Task, DecodeError> {
+        let task_type = reader.read_u8()?;
         match task_type {
-            0 => Task::Idle,
+            0 => Ok(Task::Idle),
             1 => {
-                let (point_x, binary_data) = binary_helpers::pop_f32(binary_data);
-                let (point_y, binary_data) = binary_helpers::pop_f32(binary_data);
-                Task::Move {
+                let point_x = reader.read_f32()?;
+                let point_y = reader.read_f32()?;
+                Ok(Task::Move {
                     point: point::Point::new(point_x, point_y)
-                }
+                })
             },
             2 => {
-                let (point_x, binary_data) = binary_helpers::pop_f32(binary_data);
-                let (point_y, binary_data) = binary_helpers::pop_f32(binary_data);
-                Task::AttackMove {
+                let point_x = reader.read_f32()?;
+                let point_y = reader.read_f32()?;
+                Ok(Task::AttackMove {
                     point: point::Point::new(point_x, point_y)
-                }
+                })
             },
             3 => {
-                let (point_x, binary_data) = binary_helpers::pop_f32(binary_data);
-                let (point_y, binary_data) = binary_helpers::pop_f32(binary_data);
-                let (resource_type, binary_data) = binary_helpers::pop_u8(binary_data);
-                Task::Gather {
+                let point_x = reader.read_f32()?;
+                let point_y = reader.read_f32()?;
+                let resource_type = reader.read_u8()?;
+                Ok(Task::Gather {
                     point: point::Point::new(point_x, point_y),
-                    resource_type: ResourceType::Wood,
-                }
+                    resource_type: match resource_type {
+                        0 => ResourceType::Wood,
+                        1 => ResourceType::Gold,
+                        _ => return Err(DecodeError::UnknownResourceType(resource_type)),
+                    },
+                })
             },
-            _ => {
-                println!("This should not happen, unknown Task id");
-                Task::Idle
-            }
+            _ => Err(DecodeError::UnknownTaskTag(task_type)),
         }
     }
 }
 
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
 pub enum EntityType {
     Peasant,
     Ranged,
@@ -96,6 +110,42 @@ pub enum EntityType {
 }
 
 
+impl EntityType {
+    pub fn all() -> [EntityType; 3] {
+        [EntityType::Peasant, EntityType::Ranged, EntityType::Meelee]
+    }
+}
+
+
+impl Binaryable for EntityType {
+    fn write_binary(&self, writer: &mut BinaryWriter) {
+        writer.write_u8(self.clone() as u8);
+    }
+
+    fn read_binary(reader: &mut BinaryReader) -> Result<EntityType, DecodeError> {
+        let entity_type = reader.read_u8()?;
+        match entity_type {
+            0 => Ok(EntityType::Peasant),
+            1 => Ok(EntityType::Ranged),
+            2 => Ok(EntityType::Meelee),
+            _ => Err(DecodeError::UnknownEntityType(entity_type)),
+        }
+    }
+}
+
+
+// What an entity did to its enemy on a tick
+pub enum Attack {
+    // Ranged units shoot, the projectile does the damage when it lands
+    Projectile(Projectile),
+    // Melee units hit directly at the end of their swing
+    Melee { target_id: u32, damage: u32 },
+}
+
+
+// Written to the text format as is, read through EntityText
+#[derive(Serialize, Deserialize)]
+#[serde(from = "EntityText")]
 pub struct Entity {
     location: point::Point,
     id: u32,
@@ -113,111 +163,218 @@ pub struct Entity {
     team_id: u32,
     hp: i32,
     cooldown: u32,
+    kills: u32,
 
     closest_seen_enemy_point: Option<point::Point>,
     closest_seen_enemy_id: Option<u32>,
+    // Enemy the melee swing in progress was started on
+    swing_target_id: Option<u32>,
 
     // For ai handling
     task: Task,
 }
 
 
+// Entity as read from the text format. Everything but the location, id, type
+// and team may be left out and defaults to the state of a newly created
+// entity, hp to the full hp of the type.
+#[derive(Deserialize)]
+struct EntityText {
+    location: point::Point,
+    id: u32,
+    entity_type: EntityType,
+    #[serde(default)]
+    waypoint_index: u32,
+    #[serde(default)]
+    path: Vec<point::Point>,
+    #[serde(default)]
+    orientation: u32,
+    team_id: u32,
+    #[serde(default)]
+    hp: Option<i32>,
+    #[serde(default)]
+    cooldown: u32,
+    #[serde(default)]
+    kills: u32,
+    #[serde(default)]
+    closest_seen_enemy_point: Option<point::Point>,
+    #[serde(default)]
+    closest_seen_enemy_id: Option<u32>,
+    #[serde(default)]
+    swing_target_id: Option<u32>,
+    #[serde(default)]
+    task: Task,
+}
+
+
+impl From<EntityText> for Entity {
+    fn from(text: EntityText) -> Entity {
+        Entity {
+            hp: text.hp.unwrap_or_else(|| unit_types::stats(&text.entity_type).hp),
+            location: text.location,
+            id: text.id,
+            entity_type: text.entity_type,
+            waypoint_index: text.waypoint_index,
+            path: text.path,
+            orientation: text.orientation,
+            team_id: text.team_id,
+            cooldown: text.cooldown,
+            kills: text.kills,
+            closest_seen_enemy_point: text.closest_seen_enemy_point,
+            closest_seen_enemy_id: text.closest_seen_enemy_id,
+            swing_target_id: text.swing_target_id,
+            task: text.task,
+        }
+    }
+}
+
+
 impl Binaryable for Entity {
-    fn as_binary(&self) -> Vec<u8> {
-        let mut binary_data: Vec<u8> = Vec::new();
-
-        binary_data.extend(f32_as_bytes(self.location.x));
-        binary_data.extend(f32_as_bytes(self.location.y));
-        binary_data.extend(u32_as_bytes(self.id));
-        binary_data.push(self.entity_type.clone() as u8);
-        binary_data.extend(u32_as_bytes(self.waypoint_index));
-        binary_data.extend(u32_as_bytes(self.orientation));
-        binary_data.extend(u32_as_bytes(self.team_id));
-        binary_data.extend(i32_as_bytes(self.hp));
-        binary_data.extend(u32_as_bytes(self.cooldown));
-
-        let mut path_binary: Vec<u8> = Vec::new();
-        for path_point in self.path.iter() {
-            path_binary.extend(f32_as_bytes(path_point.x));
-            path_binary.extend(f32_as_bytes(path_point.y));
-        }
-        let path_binary_length: u32 = path_binary.len() as u32;
-        binary_data.extend(u32_as_bytes(path_binary_length));
-        binary_data.extend(path_binary);
+    fn write_binary(&self, writer: &mut BinaryWriter) {
+        writer.write_f32(self.location.x);
+        writer.write_f32(self.location.y);
+        writer.write_u32(self.id);
+        self.entity_type.write_binary(writer);
+        writer.write_u32(self.waypoint_index);
+        writer.write_u32(self.orientation);
+        writer.write_u32(self.team_id);
+        writer.write_i32(self.hp);
+        writer.write_u32(self.cooldown);
+        writer.write_u32(self.kills);
+
+        let path = &self.path;
+        writer.write_padded(|writer| {
+            for path_point in path.iter() {
+                writer.write_f32(path_point.x);
+                writer.write_f32(path_point.y);
+            }
+        });
 
         match self.closest_seen_enemy_point {
             Some(point) => {
-                binary_data.push(0u8);
-                binary_data.extend(f32_as_bytes(point.x));
-                binary_data.extend(f32_as_bytes(point.y));
+                writer.write_u8(1u8);
+                writer.write_f32(point.x);
+                writer.write_f32(point.y);
+            },
+            None => {
+                writer.write_u8(0u8);
+                writer.write_f32(0.0);
+                writer.write_f32(0.0);
+            }
+        }
+        match self.closest_seen_enemy_id {
+            Some(id) => {
+                writer.write_u8(1u8);
+                writer.write_u32(id);
             },
             None => {
-                binary_data.push(1u8);
-                binary_data.extend(u32_as_bytes(0u32));
-                binary_data.extend(u32_as_bytes(0u32));
+                writer.write_u8(0u8);
+                writer.write_u32(0u32);
             }
         }
-        binary_data.extend(self.task.as_padded_binary());
-
-        binary_data
-    }
-
-    fn from_binary(binary_data: Vec<u8>) -> Entity {
-        let (location_x, binary_data) = binary_helpers::pop_f32(binary_data);
-        let (location_y, binary_data) = binary_helpers::pop_f32(binary_data);
-        let (id, binary_data) = binary_helpers::pop_u32(binary_data);
-        let (entity_type, binary_data) = binary_helpers::pop_u8(binary_data);
-        let (waypoint_index, binary_data) = binary_helpers::pop_u32(binary_data);
-        let (orientation, binary_data) = binary_helpers::pop_u32(binary_data);
-        let (team_id, binary_data) = binary_helpers::pop_u32(binary_data);
-        let (hp, binary_data) = binary_helpers::pop_i32(binary_data);
-        let (cooldown, binary_data) = binary_helpers::pop_u32(binary_data);
-        let (mut path_binary_data, binary_data) = binary_helpers::pop_padded(binary_data);
-        let (closest_seen_enemy_point_exists, binary_data) = binary_helpers::pop_u8(binary_data);
-        let (closest_seen_enemy_point_x, binary_data) = binary_helpers::pop_f32(binary_data);
-        let (closest_seen_enemy_point_y, binary_data) = binary_helpers::pop_f32(binary_data);
-        let (task_binary_data, binary_data) = binary_helpers::pop_padded(binary_data);
-
-        let mut path: Vec<point::Point> = Vec::new();
-        while path_binary_data.len() > 0 {
-            let mut point_x: f32 = 0.0;
-            let mut point_y: f32 = 0.0;
-            let (point_x, tmp1) = binary_helpers::pop_f32(path_binary_data);
-            let (point_y, tmp2) = binary_helpers::pop_f32(tmp1);
-            path_binary_data = tmp2;
+        match self.swing_target_id {
+            Some(id) => {
+                writer.write_u8(1u8);
+                writer.write_u32(id);
+            },
+            None => {
+                writer.write_u8(0u8);
+                writer.write_u32(0u32);
+            }
+        }
+        writer.write_record(&self.task);
+    }
+
+    fn read_binary(reader: &mut BinaryReader) -> Result<Entity, DecodeError> {
+        let location_x = reader.read_f32()?;
+        let location_y = reader.read_f32()?;
+        let id = reader.read_u32()?;
+        let entity_type = EntityType::read_binary(reader)?;
+        let waypoint_index = reader.read_u32()?;
+        let orientation = reader.read_u32()?;
+        let team_id = reader.read_u32()?;
+        let hp = reader.read_i32()?;
+        let cooldown = reader.read_u32()?;
+        let kills = if reader.format_version() >= KILLS_SINCE_VERSION {
+            reader.read_u32()?
+        } else {
+            0
+        };
+        let mut path_reader = reader.read_padded()?;
+        let closest_seen_enemy_point_flag = reader.read_u8()?;
+        let closest_seen_enemy_point_x = reader.read_f32()?;
+        let closest_seen_enemy_point_y = reader.read_f32()?;
+        let closest_seen_enemy_point_exists;
+        let mut closest_seen_enemy_id = None;
+        if reader.format_version() < CLOSEST_SEEN_ENEMY_ID_SINCE_VERSION {
+            closest_seen_enemy_point_exists = closest_seen_enemy_point_flag == 0;
+        } else {
+            closest_seen_enemy_point_exists = closest_seen_enemy_point_flag != 0;
+            let closest_seen_enemy_id_exists = reader.read_u8()?;
+            let id = reader.read_u32()?;
+            if closest_seen_enemy_id_exists != 0 {
+                closest_seen_enemy_id = Some(id);
+            }
+        }
+        let swing_target_id = if reader.format_version() >= SWING_TARGET_SINCE_VERSION {
+            let swing_target_id_exists = reader.read_u8()?;
+            let id = reader.read_u32()?;
+            if swing_target_id_exists != 0 { Some(id) } else { None }
+        } else {
+            // Swings used to land on whichever enemy was closest at the end
+            // of the wind up
+            let stats = unit_types::stats(&entity_type);
+            if stats.windup > 0 && cooldown > stats.cooldown - stats.windup {
+                closest_seen_enemy_id
+            } else {
+                None
+            }
+        };
+        let task = reader.read_record::<Task>()?;
+
+        if path_reader.remaining() % 8 != 0 {
+            return Err(DecodeError::LengthMismatch {
+                expected: path_reader.remaining() / 8 * 8,
+                actual: path_reader.remaining(),
+            });
+        }
+        let mut path: Vec<point::Point> = Vec::with_capacity(path_reader.remaining() / 8);
+        while !path_reader.is_empty() {
+            let point_x = path_reader.read_f32()?;
+            let point_y = path_reader.read_f32()?;
             path.push(point::Point::new(point_x, point_y));
         }
 
-        Entity {
+        Ok(Entity {
             location: point::Point::new(location_x, location_y),
             id: id,
-            entity_type: match entity_type {
-                0 => EntityType::Peasant,
-                1 => EntityType::Ranged,
-                2 => EntityType::Meelee,
-                _ => {println!("This should not happen"); EntityType::Peasant}
-            },
+            entity_type: entity_type,
             waypoint_index: waypoint_index,
             path: path,
             orientation: orientation,
             team_id: team_id,
             hp: hp,
             cooldown: cooldown,
-            closest_seen_enemy_point: if closest_seen_enemy_point_exists == 0
-                {None} else
-                {Some(point::Point::new(
+            kills: kills,
+            closest_seen_enemy_point: if closest_seen_enemy_point_exists {
+                Some(point::Point::new(
                     closest_seen_enemy_point_x,
                     closest_seen_enemy_point_y,
-                ))},
-            closest_seen_enemy_id: None,
-            task: Task::from_binary(task_binary_data),
-        }
+                ))
+            } else {
+                None
+            },
+            closest_seen_enemy_id: closest_seen_enemy_id,
+            swing_target_id: swing_target_id,
+            task: task,
+        })
     }
 }
 
 
 impl Entity {
     pub fn new(x: f32, y: f32, id: u32, team_id: u32, entity_type: EntityType) -> Entity {
+        let hp = unit_types::stats(&entity_type).hp;
         Entity {
             location: point::Point::new(x, y),
             id: id,
@@ -229,10 +386,12 @@ impl Entity {
             orientation: id % 8,
 
             team_id: team_id,
-            hp: 200,
+            hp: hp,
             cooldown: 0,
+            kills: 0,
             closest_seen_enemy_point: None,
             closest_seen_enemy_id: None,
+            swing_target_id: None,
 
             task: Task::Idle,
         }
@@ -244,13 +403,21 @@ impl Entity {
     pub fn path(&self) -> &Vec<point::Point> { &self.path }
     pub fn closest_seen_enemy_point(&self) -> &Option<point::Point> { &self.closest_seen_enemy_point }
     pub fn closest_seen_enemy_id(&self) -> &Option<u32> { &self.closest_seen_enemy_id}
+    pub fn swing_target_id(&self) -> Option<u32> { self.swing_target_id }
     pub fn location(&self) -> &point::Point { &self.location }
     pub fn id(&self) -> u32 { self.id }
     pub fn team_id(&self) -> u32 { self.team_id }
     pub fn hp(&self) -> i32 { self.hp}
+    pub fn cooldown(&self) -> u32 { self.cooldown }
+    pub fn kills(&self) -> u32 { self.kills }
+    pub fn task(&self) -> &Task { &self.task }
+
+    pub fn stats(&self) -> &'static UnitStats {
+        unit_types::stats(&self.entity_type)
+    }
 
     pub fn max_hp(&self) -> i32 {
-        200
+        self.stats().hp
     }
 
     pub fn alive(&self) -> bool {
@@ -258,28 +425,30 @@ impl Entity {
     }
 
     pub fn is_ranged(&self) -> bool {
-        match self.entity_type {
-            EntityType::Peasant => false,
-            EntityType::Meelee => false,
-            _ => true
-        }
+        self.stats().is_ranged()
     }
 
     pub fn seeing_distance(&self) -> f32 {
-        15.0
+        self.stats().seeing_distance
     }
 
     pub fn attack_distance(&self) -> f32 {
-        match self.entity_type {
-            EntityType::Ranged => 8.0,
-            // EntityType::Meelee => 1.0,
-            EntityType::Meelee => 0.6,
-            _ => 0.0
-        }
+        self.stats().attack_distance
+    }
+
+    pub fn in_reach(&self, other: &Entity) -> bool {
+        self.location.dist_to(&other.location).length() <= self.attack_distance()
     }
 
-    pub fn take_hit(&mut self, amount: u32) {
+    pub fn take_hit(&mut self, amount: u32) -> bool {
+        // Returns true if this hit killed the entity
+        let was_alive = self.alive();
         self.hp -= amount as i32;
+        was_alive && !self.alive()
+    }
+
+    pub fn credit_kill(&mut self) {
+        self.kills += 1;
     }
 
     pub fn is_inside(&self, corner_1: (f32, f32), corner_2: (f32, f32)) -> bool {
@@ -321,19 +490,24 @@ impl Entity {
     }
 
     pub fn can_attack(&self) -> bool {
-        match self.entity_type {
-            EntityType::Peasant => false,
-            _ => true
-        }
+        self.stats().can_attack()
     }
 
-    pub fn ai_stuff(&mut self, map: &map::Map) -> Option<Projectile> {
-        // Optionally returns projectile if such action was made
+    pub fn ai_stuff(&mut self, map: &map::Map) -> Option<Attack> {
+        // Optionally returns the attack if one was made
         
         if self.cooldown > 0 {
             self.cooldown -= 1;
         }
+        // A swing lands at the end of its wind up even if the entity has
+        // turned to something else since. The cooldown is still running then,
+        // so no other attack can start on the same tick.
+        let swing = self.finish_swing();
+        let attack = self.choose_action(map);
+        swing.or(attack)
+    }
 
+    fn choose_action(&mut self, map: &map::Map) -> Option<Attack> {
         let mut seeing_enemy = false;
         match &self.closest_seen_enemy_point {
             Some(_point) => {
@@ -382,24 +556,36 @@ impl Entity {
         match &self.closest_seen_enemy_point {
             Some(point) => {
                 let vector_to_enemy = self.location.dist_to(point);
-                self.move_vector(&vector_to_enemy.normalized().multiplied(0.04), true);
+                self.move_vector(&vector_to_enemy.normalized().multiplied(self.stats().move_speed), true);
             }, _ => {}
         }
     }
 
-    fn attack_enemy(&mut self) -> Option<Projectile> {
+    fn attack_enemy(&mut self) -> Option<Attack> {
         match &self.closest_seen_enemy_point {
             Some(point) => {
                 let vector_to_enemy = self.location.dist_to(point);
                 // Move towards
                 if vector_to_enemy.length() > self.attack_distance() {
-                    self.move_vector(&vector_to_enemy.normalized().multiplied(-0.04), true);
+                    self.move_vector(&vector_to_enemy.normalized().multiplied(-self.stats().move_speed), true);
                 }
                 // Shoot
+                else if self.is_ranged() {
+                    if self.cooldown == 0 {
+                        self.cooldown = self.stats().cooldown;
+                        return Some(Attack::Projectile(Projectile::new(self, point)))
+                    }
+                }
+                // Swing at the closest enemy. The cooldown counts down through
+                // the wind up, then the hit lands on that same enemy.
                 else {
                     if self.cooldown == 0 {
-                        self.cooldown = 45;
-                        return Some(Projectile::new(&self.location, point))
+                        if let Some(target_id) = self.closest_seen_enemy_id {
+                            self.cooldown = self.stats().cooldown;
+                            self.swing_target_id = Some(target_id);
+                            // Without a wind up the hit lands right away
+                            return self.finish_swing()
+                        }
                     }
                 }
             }, _ => {
@@ -409,6 +595,25 @@ impl Entity {
         None
     }
 
+    fn finish_swing(&mut self) -> Option<Attack> {
+        // The hit of the swing in progress once its wind up is over
+        let stats = self.stats();
+        if self.cooldown != stats.cooldown - stats.windup {
+            return None
+        }
+        self.swing_target_id.take().map(|target_id| Attack::Melee {
+            target_id: target_id,
+            damage: stats.damage,
+        })
+    }
+
+    pub fn cancel_swing(&mut self) {
+        // The target got out of reach or died during the wind up. The
+        // cooldown is refunded so the entity can swing again right away.
+        self.swing_target_id = None;
+        self.cooldown = 0;
+    }
+
     fn follow_path_finding(&mut self, map: &map::Map) -> bool {
         if self.path.len() == 0 {
             return false;
@@ -454,7 +659,7 @@ impl Entity {
                     self.order_stop();
                 } else {
                     let normalized = vec_to_waypoint.normalized();
-                    self.move_vector(&normalized.negated().multiplied(0.04), true);
+                    self.move_vector(&normalized.negated().multiplied(self.stats().move_speed), true);
                 }
             },
             _ => {
@@ -540,7 +745,7 @@ impl Entity {
     }
 
     pub fn interact_with(&mut self, other: &Entity, map: &map::Map) {
-        let max_dist = 0.55;
+        let max_dist = self.stats().collision_radius + other.stats().collision_radius;
 
         let dist_vect = self.location.dist_to(&other.location);
         let distance = dist_vect.length();
@@ -619,3 +824,179 @@ impl Entity {
     }
 }
 
+
+#[cfg(test)]
+pub mod tests {
+    use rand::Rng;
+    use rand::rngs::SmallRng;
+
+    use super::*;
+    use super::super::binary_helpers::tests::{
+        assert_round_trip, property_test_rng, random_f32, PROPERTY_TEST_CASES
+    };
+
+    pub fn random_point(rng: &mut SmallRng) -> point::Point {
+        point::Point::new(random_f32(rng), random_f32(rng))
+    }
+
+    pub fn random_task(rng: &mut SmallRng) -> Task {
+        match rng.gen_range(0, 4) {
+            0 => Task::Idle,
+            1 => Task::Move {point: random_point(rng)},
+            2 => Task::AttackMove {point: random_point(rng)},
+            _ => Task::Gather {
+                point: random_point(rng),
+                resource_type: if rng.gen() {ResourceType::Wood} else {ResourceType::Gold},
+            },
+        }
+    }
+
+    pub fn random_entity(rng: &mut SmallRng) -> Entity {
+        let path_length = rng.gen_range(0, 10);
+        Entity {
+            location: random_point(rng),
+            id: rng.gen(),
+            entity_type: match rng.gen_range(0, 3) {
+                0 => EntityType::Peasant,
+                1 => EntityType::Ranged,
+                _ => EntityType::Meelee,
+            },
+            waypoint_index: rng.gen(),
+            path: (0..path_length).map(|_| random_point(rng)).collect(),
+            orientation: rng.gen_range(0, 8),
+            team_id: rng.gen(),
+            hp: rng.gen(),
+            cooldown: rng.gen(),
+            kills: rng.gen(),
+            closest_seen_enemy_point: if rng.gen() {Some(random_point(rng))} else {None},
+            closest_seen_enemy_id: if rng.gen() {Some(rng.gen())} else {None},
+            swing_target_id: if rng.gen() {Some(rng.gen())} else {None},
+            task: random_task(rng),
+        }
+    }
+
+    fn assert_same_point(point_1: &point::Point, point_2: &point::Point) {
+        assert_eq!(point_1.x.to_bits(), point_2.x.to_bits());
+        assert_eq!(point_1.y.to_bits(), point_2.y.to_bits());
+    }
+
+    pub fn assert_same_entity(entity_1: &Entity, entity_2: &Entity) {
+        assert_same_point(&entity_1.location, &entity_2.location);
+        assert_eq!(entity_1.id, entity_2.id);
+        assert_eq!(entity_1.entity_type.clone() as u8, entity_2.entity_type.clone() as u8);
+        assert_eq!(entity_1.waypoint_index, entity_2.waypoint_index);
+        assert_eq!(entity_1.path.len(), entity_2.path.len());
+        for (point_1, point_2) in entity_1.path.iter().zip(entity_2.path.iter()) {
+            assert_same_point(point_1, point_2);
+        }
+        assert_eq!(entity_1.orientation, entity_2.orientation);
+        assert_eq!(entity_1.team_id, entity_2.team_id);
+        assert_eq!(entity_1.hp, entity_2.hp);
+        assert_eq!(entity_1.cooldown, entity_2.cooldown);
+        assert_eq!(entity_1.kills, entity_2.kills);
+        assert_eq!(entity_1.closest_seen_enemy_point.is_some(), entity_2.closest_seen_enemy_point.is_some());
+        if let (Some(point_1), Some(point_2)) = (&entity_1.closest_seen_enemy_point, &entity_2.closest_seen_enemy_point) {
+            assert_same_point(point_1, point_2);
+        }
+        assert_eq!(entity_1.closest_seen_enemy_id, entity_2.closest_seen_enemy_id);
+        assert_eq!(entity_1.swing_target_id, entity_2.swing_target_id);
+        assert_eq!(entity_1.task.as_binary(), entity_2.task.as_binary());
+    }
+
+    #[test]
+    fn task_round_trip() {
+        for case in 0..PROPERTY_TEST_CASES {
+            assert_round_trip(&random_task(&mut property_test_rng(case)));
+        }
+    }
+
+    #[test]
+    fn entity_round_trip() {
+        for case in 0..PROPERTY_TEST_CASES {
+            let entity = random_entity(&mut property_test_rng(case));
+            assert_same_entity(&entity, &Entity::from_binary(&entity.as_binary()).unwrap());
+        }
+    }
+
+    #[test]
+    fn entity_type_round_trip() {
+        for entity_type in [EntityType::Peasant, EntityType::Ranged, EntityType::Meelee].iter() {
+            assert_round_trip(entity_type);
+            let text = serde_json::to_string(entity_type).unwrap();
+            let decoded: EntityType = serde_json::from_str(&text).unwrap();
+            assert_eq!(decoded.as_binary(), entity_type.as_binary());
+        }
+        assert_eq!(EntityType::from_binary(&[3]).err(), Some(DecodeError::UnknownEntityType(3)));
+    }
+
+    #[test]
+    fn unknown_task_tag_is_an_error() {
+        assert_eq!(Task::from_binary(&[4]).err(), Some(DecodeError::UnknownTaskTag(4)));
+    }
+
+    #[test]
+    fn unknown_resource_type_is_an_error() {
+        let mut writer = BinaryWriter::new();
+        writer.write_u8(3);
+        writer.write_f32(1.0);
+        writer.write_f32(2.0);
+        writer.write_u8(2);
+        assert_eq!(
+            Task::from_binary(&writer.into_binary()).err(),
+            Some(DecodeError::UnknownResourceType(2)),
+        );
+    }
+
+    pub fn write_legacy_entity(writer: &mut BinaryWriter, entity: &Entity, format_version: u32) {
+        // Entity as older versions wrote it, for tests loading older saves.
+        // Leaves out the fields added since version 8, changes before that
+        // are up to the test.
+        let mut binary_data = entity.as_binary();
+        if format_version < SWING_TARGET_SINCE_VERSION {
+            // The swing target flag and id come right before the task record
+            let task_length = 4 + entity.task.as_binary().len();
+            let swing_target_position = binary_data.len() - task_length - 5;
+            binary_data.drain(swing_target_position..(swing_target_position + 5));
+        }
+        if format_version < KILLS_SINCE_VERSION {
+            // Location, id, type, waypoint index, orientation, team, hp and
+            // cooldown come before the kills
+            let kills_position = 8 + 4 + 1 + 4 + 4 + 4 + 4 + 4;
+            binary_data.drain(kills_position..(kills_position + 4));
+        }
+        writer.write_bytes(&binary_data);
+    }
+
+    #[test]
+    fn text_hp_defaults_to_full_hp() {
+        let text = r#"{"location": {"x": 0.5, "y": 0.5}, "id": 1, "entity_type": "Meelee", "team_id": 0}"#;
+        let entity: Entity = serde_json::from_str(text).unwrap();
+        assert_eq!(entity.hp(), unit_types::stats(&EntityType::Meelee).hp);
+
+        let text = r#"{"location": {"x": 0.5, "y": 0.5}, "id": 1, "entity_type": "Meelee", "team_id": 0, "hp": -2147483648}"#;
+        let entity: Entity = serde_json::from_str(text).unwrap();
+        assert_eq!(entity.hp(), i32::MIN);
+    }
+
+    #[test]
+    fn loads_version_2_closest_seen_enemy() {
+        // Version 2 wrote the point flag inverted and had no enemy id
+        let mut entity = Entity::new(1.0, 2.0, 3, 1, EntityType::Ranged);
+        entity.closest_seen_enemy_point = Some(point::Point::new(4.0, 5.0));
+        let mut writer = BinaryWriter::new();
+        write_legacy_entity(&mut writer, &entity, 2);
+        let mut binary_data = writer.into_binary();
+        let task_length = 4 + 1;
+        let enemy_id_position = binary_data.len() - task_length - 5;
+        let enemy_point_flag_position = enemy_id_position - 9;
+        binary_data[enemy_point_flag_position] = 0;
+        binary_data.drain(enemy_id_position..(enemy_id_position + 5));
+
+        let mut reader = BinaryReader::for_format_version(&binary_data, 2).unwrap();
+        let decoded = Entity::read_binary(&mut reader).unwrap();
+        reader.expect_empty().unwrap();
+        assert_eq!(decoded.closest_seen_enemy_point.unwrap().x, 4.0);
+        assert_eq!(decoded.closest_seen_enemy_point.unwrap().y, 5.0);
+        assert_eq!(decoded.closest_seen_enemy_id, None);
+    }
+}
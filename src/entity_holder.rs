@@ -1,77 +1,143 @@
-use std::collections::{HashMap};
-use std::collections::hash_map::{Values, ValuesMut};
+use std::collections::{HashMap, BTreeMap};
+use std::collections::btree_map::{Values, ValuesMut};
 use std::iter::Iterator;
+use std::time::{Duration, Instant};
 
-use multi_mut::{HashMapMultiMut, BTreeMapMultiMut};
+use multi_mut::BTreeMapMultiMut;
 
-use super::entity::{Entity, Task, EntityType};
+use super::entity::{Entity, Task, EntityType, Attack};
 use super::map;
 use super::point;
 use super::path_finder;
 use super::projectile::Projectile;
 use super::building::Building;
-use super::binary_helpers::Binaryable;
-use super::binary_helpers;
+use super::binary_helpers::{Binaryable, BinaryReader, BinaryWriter, DecodeError};
+
+use serde::{Serialize, Deserialize};
 
 
 const UNIT_CHECKUP_GRID_SIZE: i32 = 2;
 
+// Projectiles hit units this close to where they land
+const PROJECTILE_HIT_RADIUS: f32 = 0.5;
+
 
+#[derive(Serialize, Deserialize)]
 pub struct EntityHolder {
-    pub entities: HashMap<u32, Entity>,
+    // Ordered by id so the simulation always handles entities in the same order
+    #[serde(with = "entity_list", default)]
+    pub entities: BTreeMap<u32, Entity>,
+    #[serde(default)]
     pub projectiles: Vec<Projectile>,
+    #[serde(default)]
     pub buildings: Vec<Building>,
+    #[serde(default)]
     pub id_counter: u32,
 
+    #[serde(skip)]
     pub entity_location_map: HashMap<(i32, i32), Vec<u32>>,
 
     // For debug drawings of the search tree
+    #[serde(skip)]
     pub debug_search_tree:
         HashMap<(i32, i32), Option<(i32, i32)>>,
+    #[serde(skip)]
     pub debug_entity_interaction_count: u32,
+    #[serde(skip)]
+    pub debug_phase_timings: PhaseTimings,
 }
 
 
-impl Binaryable for EntityHolder {
-    fn as_binary(&self) -> Vec<u8> {
-        let mut binary_data: Vec<u8> = Vec::new();
-        
-        binary_data.extend(binary_helpers::u32_as_bytes(self.id_counter));
-        binary_data.extend(binary_helpers::iter_as_bytes(self.entities_iter()));
-        binary_data.extend(binary_helpers::iter_as_bytes(self.projectiles.iter()));
-        binary_data.extend(binary_helpers::iter_as_bytes(self.buildings.iter()));
+// Time spent in each phase of the latest entity_ai call, and in the path
+// finding of the latest order
+#[derive(Clone, Debug, Default)]
+pub struct PhaseTimings {
+    pub location_map: Duration,
+    pub closest_enemies: Duration,
+    pub ai: Duration,
+    pub interactions: Duration,
+    pub map_interactions: Duration,
+    pub projectiles: Duration,
+    pub path_finding: Duration,
+}
 
-        binary_data
+
+impl PhaseTimings {
+    pub fn phases(&self) -> [(&'static str, Duration); 7] {
+        [
+            ("location map", self.location_map),
+            ("closest enemies", self.closest_enemies),
+            ("ai", self.ai),
+            ("interactions", self.interactions),
+            ("map interactions", self.map_interactions),
+            ("projectiles", self.projectiles),
+            ("path finding", self.path_finding),
+        ]
     }
 
-    fn from_binary(binary_data: Vec<u8>) -> EntityHolder {
-        let (id_counter, binary_data) = binary_helpers::pop_u32(binary_data);
-        let (mut units_data, binary_data) = binary_helpers::pop_padded(binary_data);
-        let (mut projectiles_data, binary_data) = binary_helpers::pop_padded(binary_data);
-        let (mut buildings_data, binary_data) = binary_helpers::pop_padded(binary_data);
+    pub fn add(&mut self, other: &PhaseTimings) {
+        self.location_map += other.location_map;
+        self.closest_enemies += other.closest_enemies;
+        self.ai += other.ai;
+        self.interactions += other.interactions;
+        self.map_interactions += other.map_interactions;
+        self.projectiles += other.projectiles;
+        self.path_finding += other.path_finding;
+    }
+}
 
-        let mut new_entity_holder = EntityHolder::new();
 
-        new_entity_holder.id_counter = id_counter;
+// In the text format entities are a list ordered by id instead of a map
+mod entity_list {
+    use std::collections::BTreeMap;
 
-        while buildings_data.len() > 0 {
-            let (building_data, tmp) = binary_helpers::pop_padded(buildings_data);
-            buildings_data = tmp;
-            new_entity_holder.buildings.push(Building::from_binary(building_data));
-        }
-        while projectiles_data.len() > 0 {
-            let (projectile_data, tmp) = binary_helpers::pop_padded(projectiles_data);
-            projectiles_data = tmp;
-            new_entity_holder.projectiles.push(Projectile::from_binary(projectile_data));
+    use serde::{Serialize, Serializer, Deserialize, Deserializer};
+    use serde::de;
+
+    use super::Entity;
+
+    pub fn serialize<S: Serializer>(entities: &BTreeMap<u32, Entity>, serializer: S) -> Result<S::Ok, S::Error> {
+        let entity_list: Vec<&Entity> = entities.values().collect();
+        entity_list.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<u32, Entity>, D::Error> {
+        let mut entities: BTreeMap<u32, Entity> = BTreeMap::new();
+        for entity in Vec::<Entity>::deserialize(deserializer)? {
+            if entities.contains_key(&entity.id()) {
+                return Err(de::Error::custom(format!("duplicate entity id {}", entity.id())));
+            }
+            entities.insert(entity.id(), entity);
         }
-        while units_data.len() > 0 {
-            let (unit_data, tmp) = binary_helpers::pop_padded(units_data);
-            units_data = tmp;
-            let mut entity = Entity::from_binary(unit_data);
+        Ok(entities)
+    }
+}
+
+
+impl Binaryable for EntityHolder {
+    fn write_binary(&self, writer: &mut BinaryWriter) {
+        writer.write_u32(self.id_counter);
+        writer.write_list(self.entities_iter());
+        writer.write_list(self.projectiles.iter());
+        writer.write_list(self.buildings.iter());
+    }
+
+    fn read_binary(reader: &mut BinaryReader) -> Result<EntityHolder, DecodeError> {
+        let id_counter = reader.read_u32()?;
+        let entities = reader.read_list::<Entity>()?;
+        let projectiles = reader.read_list::<Projectile>()?;
+        let buildings = reader.read_list::<Building>()?;
+
+        let mut new_entity_holder = EntityHolder::new();
+
+        new_entity_holder.id_counter = id_counter;
+        new_entity_holder.projectiles = projectiles;
+        new_entity_holder.buildings = buildings;
+        for entity in entities {
             new_entity_holder.entities.insert(entity.id(), entity);
         }
 
-        new_entity_holder
+        Ok(new_entity_holder)
     }
 }
 
@@ -79,7 +145,7 @@ impl Binaryable for EntityHolder {
 impl EntityHolder {
     pub fn new() -> EntityHolder {
         EntityHolder {
-            entities: HashMap::new(),
+            entities: BTreeMap::new(),
             projectiles: Vec::new(),
             buildings: Vec::new(),
             id_counter: 0,
@@ -88,6 +154,7 @@ impl EntityHolder {
 
             debug_search_tree: HashMap::new(),
             debug_entity_interaction_count: 0,
+            debug_phase_timings: PhaseTimings::default(),
         }
     }
 
@@ -137,9 +204,12 @@ impl EntityHolder {
         for point in goal_points.keys() {
             distinct_points.push(*point);
         }
+        distinct_points.sort();
 
+        let start_time = Instant::now();
         let search_tree: HashMap<(i32, i32), Option<(i32, i32)>> =
             path_finder::build_search_tree(map, end_point.as_i(), &distinct_points);
+        self.debug_phase_timings.path_finding = start_time.elapsed();
 
         for point in distinct_points.iter() {
             let mut path: Vec<(i32, i32)> = Vec::new();
@@ -194,16 +264,14 @@ impl EntityHolder {
         self.buildings.push(building);
     }
 
-    pub fn add_new_entity(&mut self, x: f32, y: f32, team_id: u32) {
-        let mut entity_type: EntityType = EntityType::Meelee;
-        if self.id_counter % 3 == 0 { entity_type = EntityType::Ranged }
-        if self.id_counter % 3 == 1 { entity_type = EntityType::Peasant }
-
-        let mut new_entity = Entity::new(x, y, self.id_counter, team_id, entity_type);
+    pub fn add_new_entity(&mut self, x: f32, y: f32, team_id: u32, entity_type: EntityType) -> u32 {
+        // Returns the id of the new entity
+        let new_entity = Entity::new(x, y, self.id_counter, team_id, entity_type);
         let entity_id = new_entity.id();
         self.entities.insert(entity_id, new_entity);
 
         self.id_counter += 1;
+        entity_id
     }
 
     pub fn update_entity_location_map(&mut self) {
@@ -325,41 +393,370 @@ impl EntityHolder {
     }
 
     pub fn entities_ai_stuff(&mut self, map: &map::Map) {
+        let mut melee_hits: Vec<(u32, u32, u32)> = Vec::new();
         for entity in self.entities.values_mut() {
-            match entity.ai_stuff(map) { Some(projectile) => {
-                self.projectiles.push(projectile);
-            }, _ => {} }
+            match entity.ai_stuff(map) {
+                Some(Attack::Projectile(projectile)) => self.projectiles.push(projectile),
+                Some(Attack::Melee { target_id, damage }) => melee_hits.push((entity.id(), target_id, damage)),
+                None => {},
+            }
+        }
+        // Swings whose target got out of reach or died are cancelled. This is
+        // checked after everyone has acted, so it does not matter who acts first.
+        let cancelled_ids: Vec<u32> = self.entities.values()
+            .filter(|entity| entity.swing_target_id().is_some())
+            .filter(|entity| !self.swing_target_in_reach(entity, entity.swing_target_id().unwrap()))
+            .map(|entity| entity.id())
+            .collect();
+        for entity_id in cancelled_ids {
+            self.entities.get_mut(&entity_id).unwrap().cancel_swing();
+        }
+        // Hits land the same way, as long as the target is still in reach
+        let mut killer_ids: Vec<u32> = Vec::new();
+        for (attacker_id, target_id, damage) in melee_hits {
+            if !self.swing_target_in_reach(&self.entities[&attacker_id], target_id) {
+                self.entities.get_mut(&attacker_id).unwrap().cancel_swing();
+                continue;
+            }
+            if self.entities.get_mut(&target_id).unwrap().take_hit(damage) {
+                killer_ids.push(attacker_id);
+            }
+        }
+        self.credit_kills(killer_ids);
+    }
+
+    fn swing_target_in_reach(&self, attacker: &Entity, target_id: u32) -> bool {
+        self.entities.get(&target_id)
+            .filter(|target| target.alive())
+            .map_or(false, |target| attacker.in_reach(target))
+    }
+
+    fn credit_kills(&mut self, killer_ids: Vec<u32>) {
+        for killer_id in killer_ids {
+            if let Some(killer) = self.entities.get_mut(&killer_id) {
+                killer.credit_kill();
+            }
         }
     }
 
-    pub fn increment_projectiles(&mut self) {
+    fn projectile_hits(&self, projectile: &Projectile) -> Vec<u32> {
+        // Ids of the entities hit by a landed projectile. The intended target
+        // is hit if it is still there, otherwise the closest unit that can be
+        // hit. Splash then hits everyone else in reach.
+        let distance = |entity: &Entity| entity.location().dist_to(projectile.location()).length();
+        let direct_hit = projectile.target_id()
+            .and_then(|target_id| self.entities.get(&target_id))
+            .filter(|target| target.alive() && distance(target) < PROJECTILE_HIT_RADIUS)
+            .or_else(|| {
+                self.entities.values()
+                    .filter(|entity| projectile.can_hit(entity) && distance(entity) < PROJECTILE_HIT_RADIUS)
+                    .min_by(|entity_1, entity_2| distance(entity_1).partial_cmp(&distance(entity_2)).unwrap())
+            })
+            .map(|entity| entity.id());
+
+        let mut hits: Vec<u32> = direct_hit.into_iter().collect();
+        if projectile.splash_radius() > 0.0 {
+            hits.extend(
+                self.entities.values()
+                    .filter(|entity| {
+                        Some(entity.id()) != direct_hit &&
+                            projectile.can_hit(entity) &&
+                            distance(entity) < projectile.splash_radius()
+                    })
+                    .map(|entity| entity.id())
+            );
+        }
+        hits
+    }
+
+    pub fn increment_projectiles(&mut self, map: &map::Map) {
+        let mut killer_ids: Vec<u32> = Vec::new();
         for projectile in self.projectiles.iter_mut() {
             projectile.increment();
-            if projectile.at_location() {
-                'inner: for entity in self.entities.values_mut() {
-                    if entity.location().dist_to(projectile.location()).length() < 0.5 {
-                        entity.take_hit(12);
-                        break 'inner;
-                    }
+        }
+        // Projectiles that fly into a tree or a building stop there harmlessly
+        self.projectiles.retain(|projectile| !projectile.blocked_by(map));
+        for projectile in self.projectiles.iter() {
+            if !projectile.at_location() {
+                continue;
+            }
+            for hit_id in self.projectile_hits(projectile) {
+                let killed = self.entities.get_mut(&hit_id).unwrap().take_hit(projectile.damage());
+                if let (true, Some(shooter_id)) = (killed, projectile.shooter_id()) {
+                    killer_ids.push(shooter_id);
                 }
-                // TODO: Harm units that were hit
             }
         }
+        self.credit_kills(killer_ids);
         self.projectiles.retain(|projectile| {
             return !projectile.at_location()
         });
     }
 
     pub fn entity_ai(&mut self, map: &map::Map, tick: u32) {
+        let mut phase_start = Instant::now();
+        let mut phase_time = || {
+            let now = Instant::now();
+            let elapsed = now - phase_start;
+            phase_start = now;
+            elapsed
+        };
+
         self.update_entity_location_map();
+        self.debug_phase_timings.location_map = phase_time();
         self.update_closest_seen_enemy_points(tick);
+        self.debug_phase_timings.closest_enemies = phase_time();
         self.entities_ai_stuff(&map);
+        self.debug_phase_timings.ai = phase_time();
         self.entities_interact_with_each_other(&map, tick);
+        self.debug_phase_timings.interactions = phase_time();
         self.entities_interact_with_map(&map);
+        self.debug_phase_timings.map_interactions = phase_time();
 
-        self.increment_projectiles();
+        self.increment_projectiles(map);
 
         self.entities.retain(|_, entity| {entity.alive()});
+        self.debug_phase_timings.projectiles = phase_time();
+    }
+}
+
+
+#[cfg(test)]
+pub mod tests {
+    use std::time::Instant;
+
+    use rand::Rng;
+    use rand::rngs::SmallRng;
+
+    use super::*;
+    use super::super::binary_helpers::tests::{property_test_rng, PROPERTY_TEST_CASES};
+    use super::super::entity::tests::{random_entity, assert_same_entity, write_legacy_entity};
+    use super::super::projectile::tests::{random_projectile, set_splash, write_legacy_projectile};
+    use super::super::building::tests::random_building;
+
+    pub fn write_legacy_entity_holder(writer: &mut BinaryWriter, entity_holder: &EntityHolder, format_version: u32) {
+        // EntityHolder as older versions wrote it, for tests loading older saves
+        writer.write_u32(entity_holder.id_counter);
+        writer.write_padded(|writer| {
+            for entity in entity_holder.entities_iter() {
+                writer.write_padded(|writer| write_legacy_entity(writer, entity, format_version));
+            }
+        });
+        writer.write_padded(|writer| {
+            for projectile in entity_holder.projectiles.iter() {
+                writer.write_padded(|writer| write_legacy_projectile(writer, projectile, format_version));
+            }
+        });
+        writer.write_list(entity_holder.buildings.iter());
+    }
+
+    pub fn random_entity_holder(rng: &mut SmallRng) -> EntityHolder {
+        let mut entity_holder = EntityHolder::new();
+        entity_holder.id_counter = rng.gen();
+        for _ in 0..rng.gen_range(0, 10) {
+            let entity = random_entity(rng);
+            entity_holder.entities.insert(entity.id(), entity);
+        }
+        for _ in 0..rng.gen_range(0, 5) {
+            entity_holder.projectiles.push(random_projectile(rng));
+        }
+        for _ in 0..rng.gen_range(0, 5) {
+            entity_holder.buildings.push(random_building(rng));
+        }
+        entity_holder
+    }
+
+    pub fn assert_same_entity_holder(entity_holder_1: &EntityHolder, entity_holder_2: &EntityHolder) {
+        assert_eq!(entity_holder_1.id_counter, entity_holder_2.id_counter);
+        assert_eq!(entity_holder_1.entities.len(), entity_holder_2.entities.len());
+        for (entity_1, entity_2) in entity_holder_1.entities_iter().zip(entity_holder_2.entities_iter()) {
+            assert_same_entity(entity_1, entity_2);
+        }
+        let projectiles_1: Vec<Vec<u8>> = entity_holder_1.projectiles.iter().map(|p| p.as_binary()).collect();
+        let projectiles_2: Vec<Vec<u8>> = entity_holder_2.projectiles.iter().map(|p| p.as_binary()).collect();
+        assert_eq!(projectiles_1, projectiles_2);
+        let buildings_1: Vec<Vec<u8>> = entity_holder_1.buildings.iter().map(|b| b.as_binary()).collect();
+        let buildings_2: Vec<Vec<u8>> = entity_holder_2.buildings.iter().map(|b| b.as_binary()).collect();
+        assert_eq!(buildings_1, buildings_2);
+    }
+
+    #[test]
+    fn entity_holder_round_trip() {
+        for case in 0..PROPERTY_TEST_CASES {
+            let entity_holder = random_entity_holder(&mut property_test_rng(case));
+            let decoded = EntityHolder::from_binary(&entity_holder.as_binary()).unwrap();
+            assert_same_entity_holder(&entity_holder, &decoded);
+        }
+    }
+
+    fn shoot(entity_holder: &mut EntityHolder, shooter_id: u32, target_id: u32, end_point: point::Point) -> Projectile {
+        let (shooter, target) = entity_holder.entities.get_pair_mut(&shooter_id, &target_id).unwrap();
+        shooter.update_closest_seen_enemy(target);
+        Projectile::new(shooter, &end_point)
+    }
+
+    fn land_projectiles(entity_holder: &mut EntityHolder, map: &map::Map) {
+        while !entity_holder.projectiles.is_empty() {
+            entity_holder.increment_projectiles(map);
+        }
+    }
+
+    fn damage_taken(entity_holder: &EntityHolder) -> Vec<i32> {
+        entity_holder.entities_iter().map(|entity| entity.max_hp() - entity.hp()).collect()
+    }
+
+    #[test]
+    fn projectiles_hit_their_target_and_credit_the_kill() {
+        let map = map::Map::new(10, 4);
+        let mut entity_holder = EntityHolder::new();
+        entity_holder.add_new_entity(1.5, 1.5, 0, EntityType::Ranged);
+        // The target moved a bit after the shot, others are now closer to where it lands
+        entity_holder.add_new_entity(4.8, 1.5, 1, EntityType::Meelee);
+        entity_holder.add_new_entity(4.5, 1.6, 1, EntityType::Meelee);
+        entity_holder.add_new_entity(4.5, 1.4, 0, EntityType::Meelee);
+        let projectile = shoot(&mut entity_holder, 0, 1, point::Point::new(4.5, 1.5));
+        let damage = projectile.damage() as i32;
+        entity_holder.projectiles.push(projectile);
+        land_projectiles(&mut entity_holder, &map);
+        assert_eq!(damage_taken(&entity_holder), vec![0, damage, 0, 0]);
+        assert_eq!(entity_holder.entities[&0].kills(), 0);
+
+        let target = entity_holder.entities.get_mut(&1).unwrap();
+        let hp_left = target.hp() - damage;
+        target.take_hit(hp_left as u32);
+        let projectile = shoot(&mut entity_holder, 0, 1, point::Point::new(4.8, 1.5));
+        entity_holder.projectiles.push(projectile);
+        land_projectiles(&mut entity_holder, &map);
+        assert!(!entity_holder.entities[&1].alive());
+        assert_eq!(entity_holder.entities[&0].kills(), 1);
+    }
+
+    #[test]
+    fn projectiles_spare_teammates_without_friendly_fire() {
+        let map = map::Map::new(10, 4);
+        let mut entity_holder = EntityHolder::new();
+        entity_holder.add_new_entity(1.5, 1.5, 0, EntityType::Ranged);
+        entity_holder.add_new_entity(8.5, 1.5, 1, EntityType::Meelee);
+        // The target ran off, a teammate stands where the projectile lands
+        entity_holder.add_new_entity(4.5, 1.5, 0, EntityType::Meelee);
+        entity_holder.add_new_entity(4.8, 1.5, 1, EntityType::Meelee);
+        let projectile = shoot(&mut entity_holder, 0, 1, point::Point::new(4.5, 1.5));
+        let damage = projectile.damage() as i32;
+        entity_holder.projectiles.push(projectile);
+        land_projectiles(&mut entity_holder, &map);
+        assert_eq!(damage_taken(&entity_holder), vec![0, 0, 0, damage]);
+
+        let mut projectile = shoot(&mut entity_holder, 0, 1, point::Point::new(4.5, 1.5));
+        set_splash(&mut projectile, 1.0, true);
+        entity_holder.projectiles.push(projectile);
+        land_projectiles(&mut entity_holder, &map);
+        assert_eq!(damage_taken(&entity_holder), vec![0, 0, damage, damage * 2]);
+    }
+
+    #[test]
+    fn splash_hits_enemies_near_the_landing_point() {
+        let map = map::Map::new(10, 4);
+        let mut entity_holder = EntityHolder::new();
+        entity_holder.add_new_entity(1.5, 1.5, 0, EntityType::Ranged);
+        entity_holder.add_new_entity(6.5, 1.5, 1, EntityType::Meelee);
+        entity_holder.add_new_entity(6.5, 2.5, 1, EntityType::Meelee);
+        entity_holder.add_new_entity(6.5, 0.5, 0, EntityType::Meelee);
+        entity_holder.add_new_entity(8.5, 1.5, 1, EntityType::Meelee);
+        let mut projectile = shoot(&mut entity_holder, 0, 1, point::Point::new(6.5, 1.5));
+        set_splash(&mut projectile, 1.5, false);
+        let damage = projectile.damage() as i32;
+        entity_holder.projectiles.push(projectile);
+        land_projectiles(&mut entity_holder, &map);
+        assert_eq!(damage_taken(&entity_holder), vec![0, damage, damage, 0, 0]);
+    }
+
+    #[test]
+    fn trees_next_to_the_target_block_projectiles() {
+        let mut map = map::Map::new(10, 4);
+        map.set_second_layer(6, 1, map::SecondLevelType::Tree);
+        let mut entity_holder = EntityHolder::new();
+        entity_holder.add_new_entity(1.5, 1.5, 0, EntityType::Ranged);
+        entity_holder.add_new_entity(7.5, 1.5, 1, EntityType::Meelee);
+        entity_holder.add_new_entity(7.5, 2.5, 1, EntityType::Meelee);
+        let projectile = shoot(&mut entity_holder, 0, 1, point::Point::new(7.5, 1.5));
+        entity_holder.projectiles.push(projectile);
+        let projectile = shoot(&mut entity_holder, 0, 2, point::Point::new(7.5, 2.5));
+        let damage = projectile.damage() as i32;
+        entity_holder.projectiles.push(projectile);
+        land_projectiles(&mut entity_holder, &map);
+        assert_eq!(damage_taken(&entity_holder), vec![0, 0, damage]);
+    }
+
+    fn entity_holder_with_entities(amount: u32) -> EntityHolder {
+        let mut entity_holder = EntityHolder::new();
+        for n in 0..amount {
+            entity_holder.add_new_entity((n % 500) as f32 + 0.5, (n / 500) as f32 + 0.5, n % 2, EntityType::Ranged);
+        }
+        entity_holder
+    }
+
+    pub fn battle_entity_holder(amount: u32) -> (EntityHolder, map::Map) {
+        // Two square armies facing each other across a small gap
+        let side = ((amount / 2) as f32).sqrt().ceil() as u32;
+        let map = map::Map::new(side * 2 + 20, side + 10);
+        let mut entity_holder = EntityHolder::new();
+        for n in 0..amount {
+            let team_id = n % 2;
+            let (column, row) = ((n / 2) % side, (n / 2) / side);
+            let x = if team_id == 0 { 5 + column } else { side + 15 + column };
+            let entity_type = if (n / 2) % 2 == 0 { EntityType::Ranged } else { EntityType::Meelee };
+            entity_holder.add_new_entity(x as f32 + 0.3, row as f32 + 5.6, team_id, entity_type);
+        }
+        (entity_holder, map)
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_entity_ai_phases() {
+        // Run with: cargo test --release -- --ignored --nocapture bench_
+        let ticks = 20;
+        for amount in [1_000, 5_000, 20_000].iter() {
+            let (mut entity_holder, map) = battle_entity_holder(*amount);
+            let mut total = PhaseTimings::default();
+            for tick in 0..ticks {
+                entity_holder.entity_ai(&map, tick);
+                total.add(&entity_holder.debug_phase_timings);
+            }
+
+            println!("{} units, average per tick over {} ticks:", amount, ticks);
+            for (name, duration) in total.phases().iter() {
+                println!("  {:>16}: {:>8.3} ms", name, duration.as_secs_f64() * 1000.0 / ticks as f64);
+            }
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_entity_holder_load_time_is_linear() {
+        // Run with: cargo test --release -- --ignored --nocapture bench_
+        let mut nanos_per_entity: Vec<f64> = Vec::new();
+
+        for amount in [10_000, 25_000, 50_000, 100_000].iter() {
+            let binary_data = entity_holder_with_entities(*amount).as_binary();
+
+            let start_time = Instant::now();
+            let loaded = EntityHolder::from_binary(&binary_data).unwrap();
+            let elapsed = start_time.elapsed();
+
+            assert_eq!(loaded.entities.len(), *amount as usize);
+            let per_entity = elapsed.as_nanos() as f64 / *amount as f64;
+            println!(
+                "{:>7} entities, {:>9} bytes: {:>8.2} ms, {:>8.1} ns per entity",
+                amount,
+                binary_data.len(),
+                elapsed.as_secs_f64() * 1000.0,
+                per_entity,
+            );
+            nanos_per_entity.push(per_entity);
+        }
+
+        // Quadratic loading would make the largest dump ~10x slower per entity
+        assert!(nanos_per_entity[3] < nanos_per_entity[0] * 3.0);
     }
 }
 
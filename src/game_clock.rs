@@ -0,0 +1,98 @@
+// Simulation rate, independent of the render framerate. Unit speeds were
+// tuned when the game ticked once per frame at 60 Hz vsync.
+pub const TICKS_PER_SECOND: u32 = 60;
+
+// Game speed multipliers, switched with faster() and slower()
+const GAME_SPEEDS: [f32; 4] = [0.5, 1.0, 2.0, 4.0];
+const NORMAL_SPEED_INDEX: usize = 1;
+
+// After a long frame (window dragged, debugger) the game skips ahead at most
+// this many ticks instead of freezing the frame while it catches up
+const MAX_TICKS_PER_FRAME: u32 = 32;
+
+
+// Decides how many ticks to simulate each frame from the time the frame took
+pub struct GameClock {
+    // Game time not simulated yet, in ticks
+    accumulator: f32,
+    speed_index: usize,
+    pub paused: bool,
+}
+
+
+impl GameClock {
+    pub fn new() -> GameClock {
+        GameClock {
+            accumulator: 0.0,
+            speed_index: NORMAL_SPEED_INDEX,
+            paused: false,
+        }
+    }
+
+    pub fn speed(&self) -> f32 { GAME_SPEEDS[self.speed_index] }
+
+    pub fn faster(&mut self) {
+        self.speed_index = (self.speed_index + 1).min(GAME_SPEEDS.len() - 1);
+    }
+
+    pub fn slower(&mut self) {
+        self.speed_index = self.speed_index.saturating_sub(1);
+    }
+
+    pub fn advance(&mut self, frame_seconds: f32) -> u32 {
+        // Returns the number of ticks to simulate for a frame that took frame_seconds
+        if self.paused {
+            return 0;
+        }
+        self.accumulator += frame_seconds * TICKS_PER_SECOND as f32 * self.speed();
+        let ticks = self.accumulator as u32;
+        self.accumulator -= ticks as f32;
+        ticks.min(MAX_TICKS_PER_FRAME)
+    }
+
+    pub fn interpolation(&self) -> f32 {
+        // How far the frame is between the last simulated tick (0.0) and the next one (1.0)
+        self.accumulator
+    }
+}
+
+
+impl Default for GameClock {
+    fn default() -> GameClock {
+        GameClock::new()
+    }
+}
+
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn ticks_follow_time_not_frames() {
+        let mut clock = GameClock::new();
+        let frame_seconds = 1.0 / 144.0;
+        let ticks: u32 = (0..144).map(|_| clock.advance(frame_seconds)).sum();
+        assert!((ticks as i32 - TICKS_PER_SECOND as i32).abs() <= 1);
+        assert!(clock.interpolation() >= 0.0 && clock.interpolation() < 1.0);
+
+        // Slow frames run several ticks, but only up to the limit
+        assert_eq!(clock.advance(1.0 / 20.0), 3);
+        assert_eq!(clock.advance(10.0), MAX_TICKS_PER_FRAME);
+    }
+
+    #[test]
+    fn speed_controls() {
+        let mut clock = GameClock::new();
+        clock.faster();
+        assert_eq!(clock.advance(0.25), TICKS_PER_SECOND / 2);
+        clock.slower();
+        clock.slower();
+        clock.slower();
+        assert_eq!(clock.speed(), 0.5);
+        assert_eq!(clock.advance(0.5), TICKS_PER_SECOND / 4);
+
+        clock.paused = true;
+        assert_eq!(clock.advance(1.0), 0);
+    }
+}
@@ -0,0 +1,104 @@
+use rand::{RngCore, Error};
+
+use serde::{Serialize, Deserialize};
+
+
+// SplitMix64, see http://xoshiro.di.unimi.it/splitmix64.c
+// Implemented here instead of using one of the rand generators so that the
+// sequence, and with it saved games, stays the same across rand versions.
+const GOLDEN_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct GameRng {
+    state: u64,
+}
+
+
+impl GameRng {
+    pub fn new(seed: u64) -> GameRng {
+        GameRng { state: seed }
+    }
+
+    // Continues from a state saved with state()
+    pub fn from_state(state: u64) -> GameRng {
+        GameRng { state: state }
+    }
+
+    pub fn state(&self) -> u64 { self.state }
+
+    // Independent generator for a subsystem, so that the subsystem drawing
+    // more or fewer numbers does not shift the numbers everything else gets
+    pub fn fork(&mut self) -> GameRng {
+        GameRng::new(self.next_u64())
+    }
+}
+
+
+impl Default for GameRng {
+    fn default() -> GameRng {
+        GameRng::new(0)
+    }
+}
+
+
+impl RngCore for GameRng {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(GOLDEN_GAMMA);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+pub mod tests {
+    use rand::Rng;
+
+    use super::*;
+
+    #[test]
+    fn same_seed_gives_same_numbers() {
+        let mut first = GameRng::new(1234);
+        let mut second = GameRng::new(1234);
+        for _ in 0..100 {
+            assert_eq!(first.next_u64(), second.next_u64());
+        }
+        assert_ne!(GameRng::new(1).next_u64(), GameRng::new(2).next_u64());
+
+        // Reference values of SplitMix64 seeded with 0
+        let mut rng = GameRng::new(0);
+        assert_eq!(rng.next_u64(), 0xe220_a839_7b1d_cdaf);
+        assert_eq!(rng.next_u64(), 0x6e78_9e6a_a1b9_65f4);
+    }
+
+    #[test]
+    fn continues_from_saved_state() {
+        let mut rng = GameRng::new(99);
+        rng.gen_range(0, 10);
+        let mut continued = GameRng::from_state(rng.state());
+        for _ in 0..100 {
+            let value: f32 = rng.gen_range(-1.0, 1.0);
+            assert_eq!(continued.gen_range(-1.0, 1.0), value);
+        }
+    }
+}
@@ -1,25 +1,130 @@
 
 
-use std::fs;
 use std::fs::File;
 use std::io::Read;
+use std::mem;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 
 
 use super::point::Point;
 use super::map::{Map, GroundType};
 use super::entity_holder::{EntityHolder};
 use super::entity::{EntityType, Task};
+use super::game_rng::GameRng;
 
-use super::binary_helpers::Binaryable;
-use super::binary_helpers;
+use super::binary_helpers::{Binaryable, BinaryReader, BinaryWriter, DecodeError, SAVE_FORMAT_VERSION, stable_hash};
 
+use serde::{Serialize, Deserialize};
 
+
+// Save files start with these magic bytes followed by the rest of SaveHeader.
+// Files written before the header existed start directly with the tick and
+// are treated as format version 0.
+pub const SAVE_FILE_MAGIC: &[u8; 4] = b"ORTS";
+
+// Format version 1 wrote the header big-endian, so its version reads as this
+const BIG_ENDIAN_VERSION_1: u32 = 0x0100_0000;
+
+// Save files since this version end with a CRC-32 of everything before it
+const CHECKSUM_SINCE_VERSION: u32 = 4;
+
+// Game states since this version store the world seed and the random
+// number generator state
+const SEED_SINCE_VERSION: u32 = 5;
+
+// Files with this extension are loaded as text (JSON) instead of binary
+pub const TEXT_SAVE_EXTENSION: &str = ".json";
+
+
+#[derive(Serialize)]
+pub struct SaveHeader {
+    pub format_version: u32,
+    pub engine_version: String,
+    pub created_at: u64, // Seconds since unix epoch
+}
+
+
+impl Binaryable for SaveHeader {
+    fn write_binary(&self, writer: &mut BinaryWriter) {
+        writer.write_bytes(SAVE_FILE_MAGIC);
+        writer.write_u32(self.format_version);
+        writer.write_padded_bytes(self.engine_version.as_bytes());
+        writer.write_u64(self.created_at);
+    }
+
+    fn read_binary(reader: &mut BinaryReader) -> Result<SaveHeader, DecodeError> {
+        // Switches the reader to the format version of the file, so the game
+        // state following the header can be read with the same reader
+
+        // Files without the magic bytes predate the header
+        if !SaveHeader::is_present(reader) {
+            reader.set_format_version(0)?;
+            return Ok(SaveHeader::legacy());
+        }
+
+        let _magic = reader.read_bytes(SAVE_FILE_MAGIC.len())?;
+        let format_version = match reader.read_u32()? {
+            BIG_ENDIAN_VERSION_1 => 1,
+            format_version => format_version,
+        };
+        reader.set_format_version(format_version)?;
+        let engine_version_data = reader.read_padded_bytes()?;
+        let created_at = reader.read_u64()?;
+
+        Ok(SaveHeader {
+            format_version: format_version,
+            engine_version: String::from_utf8_lossy(engine_version_data).to_string(),
+            created_at: created_at,
+        })
+    }
+}
+
+
+impl SaveHeader {
+    pub fn new() -> SaveHeader {
+        SaveHeader {
+            format_version: SAVE_FORMAT_VERSION,
+            engine_version: env!("CARGO_PKG_VERSION").to_string(),
+            created_at: match SystemTime::now().duration_since(UNIX_EPOCH) {
+                Ok(duration) => duration.as_secs(),
+                Err(_) => 0,
+            },
+        }
+    }
+
+    pub fn legacy() -> SaveHeader {
+        SaveHeader {
+            format_version: 0,
+            engine_version: "unknown".to_string(),
+            created_at: 0,
+        }
+    }
+
+    pub fn is_present(reader: &BinaryReader) -> bool {
+        let mut peek_reader = BinaryReader::new(reader.peek_bytes());
+        match peek_reader.read_bytes(SAVE_FILE_MAGIC.len()) {
+            Ok(magic) => magic == SAVE_FILE_MAGIC,
+            Err(_) => false,
+        }
+    }
+}
+
+
+impl Default for SaveHeader {
+    fn default() -> SaveHeader {
+        SaveHeader::new()
+    }
+}
+
+
+#[derive(Clone, Serialize, Deserialize)]
 pub enum GameEvent {
     OrderUnits {
         task: Task,
+        #[serde(with = "unit_id_set")]
         unit_ids: HashMap<u32, bool>,
     },
     InsertUnit {
@@ -37,73 +142,317 @@ pub enum GameEvent {
 }
 
 
+// Units of an order are the keys of unit_ids, in the text format they are a
+// list of ids in ascending order
+mod unit_id_set {
+    use std::collections::HashMap;
+
+    use serde::{Serialize, Serializer, Deserialize, Deserializer};
+
+    pub fn serialize<S: Serializer>(unit_ids: &HashMap<u32, bool>, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut unit_id_list: Vec<u32> = unit_ids.keys().cloned().collect();
+        unit_id_list.sort();
+        unit_id_list.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HashMap<u32, bool>, D::Error> {
+        Ok(Vec::<u32>::deserialize(deserializer)?.into_iter().map(|unit_id| (unit_id, true)).collect())
+    }
+}
+
+
+impl Binaryable for GameEvent {
+    fn write_binary(&self, writer: &mut BinaryWriter) {
+        match self {
+            GameEvent::OrderUnits { task, unit_ids } => {
+                writer.write_u8(0u8);
+                writer.write_record(task);
+                // Sorted so the same order always encodes the same way
+                let mut unit_ids: Vec<(&u32, &bool)> = unit_ids.iter().collect();
+                unit_ids.sort();
+                writer.write_padded(|writer| {
+                    for (unit_id, selected) in unit_ids {
+                        writer.write_u32(*unit_id);
+                        writer.write_u8(*selected as u8);
+                    }
+                });
+            },
+            GameEvent::InsertUnit { location, team_id, unit_type } => {
+                writer.write_u8(1u8);
+                writer.write_f32(location.x);
+                writer.write_f32(location.y);
+                writer.write_u32(*team_id);
+                unit_type.write_binary(writer);
+            },
+            GameEvent::SetMapPoint { location, ground_type } => {
+                writer.write_u8(2u8);
+                writer.write_i32(location.0);
+                writer.write_i32(location.1);
+                ground_type.write_binary(writer);
+            },
+            GameEvent::AddBuilding { location } => {
+                writer.write_u8(3u8);
+                writer.write_i32(location.0);
+                writer.write_i32(location.1);
+            },
+        }
+    }
+
+    fn read_binary(reader: &mut BinaryReader) -> Result<GameEvent, DecodeError> {
+        let event_type = reader.read_u8()?;
+        match event_type {
+            0 => {
+                let task = reader.read_record::<Task>()?;
+                let mut unit_id_reader = reader.read_padded()?;
+                let mut unit_ids: HashMap<u32, bool> = HashMap::new();
+                while !unit_id_reader.is_empty() {
+                    let unit_id = unit_id_reader.read_u32()?;
+                    let selected = unit_id_reader.read_u8()?;
+                    unit_ids.insert(unit_id, selected != 0);
+                }
+                Ok(GameEvent::OrderUnits { task: task, unit_ids: unit_ids })
+            },
+            1 => {
+                let location_x = reader.read_f32()?;
+                let location_y = reader.read_f32()?;
+                let team_id = reader.read_u32()?;
+                let unit_type = EntityType::read_binary(reader)?;
+                Ok(GameEvent::InsertUnit {
+                    location: Point::new(location_x, location_y),
+                    team_id: team_id,
+                    unit_type: unit_type,
+                })
+            },
+            2 => {
+                let x = reader.read_i32()?;
+                let y = reader.read_i32()?;
+                let ground_type = GroundType::read_binary(reader)?;
+                Ok(GameEvent::SetMapPoint { location: (x, y), ground_type: ground_type })
+            },
+            3 => {
+                let x = reader.read_i32()?;
+                let y = reader.read_i32()?;
+                Ok(GameEvent::AddBuilding { location: (x, y) })
+            },
+            _ => Err(DecodeError::UnknownEventTag(event_type)),
+        }
+    }
+}
+
+
+// GameEvent together with the tick it is applied on. Every do_tick first
+// advances the tick and applies the events scheduled for it, in the order
+// they were scheduled, before any entity acts. Events scheduled for a tick
+// that has already passed are applied on the next tick.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ScheduledEvent {
+    pub tick: u32,
+    pub game_event: GameEvent,
+}
+
+
+impl Binaryable for ScheduledEvent {
+    fn write_binary(&self, writer: &mut BinaryWriter) {
+        writer.write_u32(self.tick);
+        writer.write_record(&self.game_event);
+    }
+
+    fn read_binary(reader: &mut BinaryReader) -> Result<ScheduledEvent, DecodeError> {
+        let tick = reader.read_u32()?;
+        let game_event = reader.read_record::<GameEvent>()?;
+        Ok(ScheduledEvent { tick: tick, game_event: game_event })
+    }
+}
+
+
+impl ScheduledEvent {
+    // Event scripts are JSON lists of scheduled events
+    pub fn list_from_json(text: &str) -> Result<Vec<ScheduledEvent>, DecodeError> {
+        serde_json::from_str(text).map_err(|error| DecodeError::InvalidText(error.to_string()))
+    }
+
+    pub fn list_to_json(scheduled_events: &[ScheduledEvent]) -> String {
+        serde_json::to_string_pretty(scheduled_events).expect("ScheduledEvent is always representable as JSON")
+    }
+}
+
+
+#[derive(Serialize, Deserialize)]
 pub struct GameState {
+    #[serde(default)]
     tick: u32,
 
+    // Seed the world was generated from. All randomness in the simulation
+    // comes from rng, which starts out seeded with it.
+    #[serde(default)]
+    seed: u64,
+    #[serde(default)]
+    rng: GameRng,
+
     map: Map,
+    #[serde(default = "EntityHolder::new")]
     entity_holder: EntityHolder,
 
-    event_log: Vec<GameEvent>,
+    // Pending events ordered by tick, events of the same tick in the order
+    // they were scheduled
+    #[serde(skip)]
+    event_queue: VecDeque<ScheduledEvent>,
+    // Applied events are collected here while a replay is being recorded
+    #[serde(skip)]
+    recorded_events: Option<Vec<ScheduledEvent>>,
+
+    // Logs state_hash every n:th tick to find the first tick where two games
+    // diverge, 0 disables logging
+    #[serde(skip)]
+    pub debug_state_hash_interval: u32,
 }
 
 
-impl binary_helpers::Binaryable for GameState {
-    fn as_binary(&self) -> Vec<u8> {
-        let mut binary_data: Vec<u8> = Vec::new();
+fn read_file(file_name: &str) -> Result<Vec<u8>, DecodeError> {
+    let mut binary_data: Vec<u8> = Vec::new();
+    File::open(file_name)
+        .and_then(|mut file_object| file_object.read_to_end(&mut binary_data))
+        .map_err(|error| DecodeError::Io(format!("{}: {}", file_name, error)))?;
+    Ok(binary_data)
+}
 
-        binary_data.extend(binary_helpers::u32_as_bytes(self.tick));
-        binary_data.extend(self.map.as_padded_binary());
-        binary_data.extend(self.entity_holder.as_padded_binary());
 
-        binary_data
+impl Binaryable for GameState {
+    fn write_binary(&self, writer: &mut BinaryWriter) {
+        writer.write_u32(self.tick);
+        writer.write_u64(self.seed);
+        writer.write_u64(self.rng.state());
+        writer.write_record(&self.map);
+        writer.write_record(&self.entity_holder);
     }
 
-    fn from_binary(binary_data: Vec<u8>) -> GameState {
-        println!("Loading GameState from binary");
-
-        let (tick ,binary_data) = binary_helpers::pop_u32(binary_data);
-        let (map_data, binary_data) = binary_helpers::pop_padded(binary_data);
-        let (entity_data, binary_data) = binary_helpers::pop_padded(binary_data);
+    fn read_binary(reader: &mut BinaryReader) -> Result<GameState, DecodeError> {
+        let tick = reader.read_u32()?;
+        let (seed, rng) = if reader.format_version() >= SEED_SINCE_VERSION {
+            (reader.read_u64()?, GameRng::from_state(reader.read_u64()?))
+        } else {
+            (0, GameRng::new(0))
+        };
+        let map = reader.read_record::<Map>()?;
+        let entity_holder = reader.read_record::<EntityHolder>()?;
 
-        println!("Map data: {}", map_data.len());
-        println!("Entity data: {}", entity_data.len());
-        println!("Rest of the data: {}", binary_data.len());
-
-        GameState {
+        Ok(GameState {
             tick: tick,
-            map: Map::from_binary(map_data),
-            entity_holder: EntityHolder::from_binary(entity_data),
-            event_log: Vec::new(),
-        }
+            seed: seed,
+            rng: rng,
+            map: map,
+            entity_holder: entity_holder,
+            event_queue: VecDeque::new(),
+            recorded_events: None,
+            debug_state_hash_interval: 0,
+        })
     }
 }
 
 
 impl GameState {
     pub fn new() -> GameState {
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs() ^ ((duration.subsec_nanos() as u64) << 32))
+            .unwrap_or(0);
+        GameState::new_with_seed(seed)
+    }
+
+    pub fn new_with_seed(seed: u64) -> GameState {
+        // The same seed always gives the same world and the same simulation
+        let mut rng = GameRng::new(seed);
+        let map = Map::new_random(100, 50, &mut rng.fork());
         GameState {
             tick: 0,
-            map: Map::new_random(100, 50),
+            seed: seed,
+            rng: rng,
+            map: map,
             entity_holder: EntityHolder::new(),
-            event_log: Vec::new(),
+            event_queue: VecDeque::new(),
+            recorded_events: None,
+            debug_state_hash_interval: 0,
         }
     }
 
-    pub fn from_file_name(file_name: String) -> GameState {
-        let mut file_object = File::open(file_name).expect("File name not found");
-        let mut binary_data: Vec<u8> = Vec::new();
-        file_object.read_to_end(&mut binary_data).expect("File reading failed");
-        GameState::from_binary(binary_data)
+    pub fn from_file_name(file_name: String) -> Result<GameState, DecodeError> {
+        if file_name.ends_with(TEXT_SAVE_EXTENSION) {
+            let text = String::from_utf8(read_file(&file_name)?)
+                .map_err(|error| DecodeError::InvalidText(error.to_string()))?;
+            return GameState::from_json(&text);
+        }
+
+        let (header, game_state) = GameState::load_save_file(&file_name)?;
+        println!(
+            "Save file format version: {}, engine version: {}, created at: {}",
+            header.format_version,
+            header.engine_version,
+            header.created_at,
+        );
+        Ok(game_state)
     }
 
+    pub fn load_save_file(file_name: &str) -> Result<(SaveHeader, GameState), DecodeError> {
+        GameState::from_save_binary(&read_file(file_name)?)
+    }
+
+    pub fn from_save_binary(binary_data: &[u8]) -> Result<(SaveHeader, GameState), DecodeError> {
+        // Reads a whole save file: the header followed by the game state
+        let mut reader = BinaryReader::new(binary_data);
+        let header = SaveHeader::read_binary(&mut reader)?;
+        if header.format_version >= CHECKSUM_SINCE_VERSION {
+            reader.verify_trailing_checksum()?;
+        }
+        let game_state = GameState::read_binary(&mut reader)?;
+        reader.expect_empty()?;
+        Ok((header, game_state))
+    }
+
+    pub fn as_save_binary(&self) -> Vec<u8> {
+        let mut writer = BinaryWriter::new();
+        SaveHeader::new().write_binary(&mut writer);
+        self.write_binary(&mut writer);
+        writer.write_checksum();
+        writer.into_binary()
+    }
+
+    pub fn from_json(text: &str) -> Result<GameState, DecodeError> {
+        let value: serde_json::Value = serde_json::from_str(text)
+            .map_err(|error| DecodeError::InvalidText(error.to_string()))?;
+        let has_rng_state = value.get("rng").is_some();
+        let mut game_state: GameState = serde_json::from_value(value)
+            .map_err(|error| DecodeError::InvalidText(error.to_string()))?;
+
+        // Hand written scenarios may give only the seed
+        if !has_rng_state {
+            game_state.rng = GameRng::new(game_state.seed);
+        }
+
+        // Hand written scenarios may leave out the id counter
+        let entity_holder = &mut game_state.entity_holder;
+        let next_free_id = entity_holder.entities.keys().max().map_or(0, |id| id + 1);
+        entity_holder.id_counter = entity_holder.id_counter.max(next_free_id);
+
+        Ok(game_state)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("GameState is always representable as JSON")
+    }
+
+    pub fn seed(&self) -> u64 { self.seed }
+    pub fn rng(&mut self) -> &mut GameRng { &mut self.rng }
+
     pub fn map(&self) -> &Map { &self.map }
     pub fn entity_holder(&self) -> &EntityHolder { &self.entity_holder }
 
     pub fn tick(&self) -> u32 { self.tick }
     pub fn do_tick(&mut self) {
         self.tick += 1;
-        self.entity_holder.entity_ai(&self.map, self.tick);
-        while let Some(game_event) = self.event_log.pop() {
+        while self.event_queue.front().is_some_and(|scheduled_event| scheduled_event.tick <= self.tick) {
+            let game_event = self.event_queue.pop_front().unwrap().game_event;
+            if let Some(recorded_events) = self.recorded_events.as_mut() {
+                recorded_events.push(ScheduledEvent { tick: self.tick, game_event: game_event.clone() });
+            }
             match game_event {
                 GameEvent::OrderUnits { task, unit_ids } => {
                     self.entity_holder.order_entities(
@@ -114,7 +463,7 @@ impl GameState {
                 },
                 GameEvent::InsertUnit { location, team_id, unit_type } => {
                     self.entity_holder.add_new_entity(
-                        location.x, location.y, team_id
+                        location.x, location.y, team_id, unit_type
                     );
                 },
                 GameEvent::SetMapPoint { location, ground_type } => {
@@ -127,17 +476,575 @@ impl GameState {
                 }
             }
         }
+        self.entity_holder.entity_ai(&self.map, self.tick);
+
+        if self.debug_state_hash_interval != 0 && self.tick.is_multiple_of(self.debug_state_hash_interval) {
+            println!("Tick {} state hash {:016x}", self.tick, self.state_hash());
+        }
+    }
+
+    pub fn state_hash(&self) -> u64 {
+        // Hash of everything the simulation depends on
+        let entity_holder = &self.entity_holder;
+        let mut writer = BinaryWriter::new();
+        writer.write_u32(self.tick);
+        writer.write_u64(self.rng.state());
+        writer.write_record(&self.map);
+        writer.write_u32(entity_holder.id_counter);
+        writer.write_list(entity_holder.entities_iter());
+        writer.write_list(entity_holder.projectiles.iter());
+        writer.write_list(entity_holder.buildings.iter());
+        stable_hash(&writer.into_binary())
     }
 
     pub fn dispatch_event(&mut self, game_event: GameEvent) {
-        self.event_log.push(game_event);
+        // Applied at the start of the next tick
+        let tick = self.tick + 1;
+        self.schedule_event(tick, game_event);
     }
 
-    pub fn save_to_file(&self) {
-        println!("GameState saved to file");
-        let binary_data = self.as_binary();
+    pub fn schedule_event(&mut self, tick: u32, game_event: GameEvent) {
+        // Inserted after every event scheduled for the same or an earlier tick
+        let index = self.event_queue.iter()
+            .rposition(|scheduled_event| scheduled_event.tick <= tick)
+            .map_or(0, |index| index + 1);
+        self.event_queue.insert(index, ScheduledEvent { tick: tick, game_event: game_event });
+    }
 
-        fs::write("saved_game.dat", binary_data).expect("Unable to write file");
+    pub fn discard_pending_events(&mut self) {
+        self.event_queue.clear();
+    }
+
+    pub fn start_recording(&mut self) {
+        self.recorded_events = Some(Vec::new());
+    }
+
+    pub fn take_recorded_events(&mut self) -> Vec<ScheduledEvent> {
+        // Events applied since the last call, empty when not recording
+        match self.recorded_events.as_mut() {
+            Some(recorded_events) => mem::take(recorded_events),
+            None => Vec::new(),
+        }
     }
 }
 
+
+#[cfg(test)]
+pub mod tests {
+    use rand::{Rng, RngCore};
+    use rand::rngs::SmallRng;
+
+    use super::*;
+    use super::super::binary_helpers::tests::{assert_round_trip, property_test_rng, PROPERTY_TEST_CASES};
+    use super::super::map::tests::random_map;
+    use super::super::entity::Entity;
+    use super::super::entity::tests::{random_point, random_task};
+    use super::super::entity_holder::tests::{random_entity_holder, assert_same_entity_holder, write_legacy_entity_holder};
+
+    fn legacy_game_state_binary() -> Vec<u8> {
+        // Version 0 and 1 game state with a 2x1 map and one building
+        vec![
+            0, 0, 0, 7, // Tick
+            0, 0, 0, 12, // Map length
+            0, 0, 0, 2, // Width
+            0, 0, 0, 1, // Height
+            1, 2, // Ground layer
+            0, 2, // Second layer
+            0, 0, 0, 28, // Entity holder length
+            0, 0, 0, 5, // Id counter
+            0, 0, 0, 0, // Entities
+            0, 0, 0, 0, // Projectiles
+            0, 0, 0, 12, // Buildings
+            0, 0, 0, 8, // Building length
+            0, 0, 0, 3, // X
+            255, 255, 255, 254, // Y
+        ]
+    }
+
+    fn assert_legacy_game_state(game_state: &GameState) {
+        assert_eq!(game_state.tick(), 7);
+        assert_eq!(game_state.map().width(), 2);
+        assert_eq!(game_state.map().height(), 1);
+        assert!(game_state.map().get_at(1, 0) == GroundType::Water);
+        assert_eq!(game_state.entity_holder().id_counter, 5);
+        assert_eq!(game_state.entity_holder().buildings[0].x(), 3);
+        assert_eq!(game_state.entity_holder().buildings[0].y(), -2);
+        assert_eq!(game_state.seed(), 0);
+    }
+
+    pub fn write_legacy_game_state(writer: &mut BinaryWriter, game_state: &GameState, format_version: u32) {
+        // GameState as versions 4 and later wrote it, for tests loading older
+        // saves. The map layout has not changed since version 4.
+        writer.write_u32(game_state.tick);
+        if format_version >= SEED_SINCE_VERSION {
+            writer.write_u64(game_state.seed);
+            writer.write_u64(game_state.rng.state());
+        }
+        writer.write_record(&game_state.map);
+        writer.write_padded(|writer| write_legacy_entity_holder(writer, &game_state.entity_holder, format_version));
+    }
+
+    pub fn battle_game_state() -> GameState {
+        // Two lines of ranged units that walk into range of each other and
+        // start shooting. Units are spaced so that no distances are tied.
+        let mut entity_holder = EntityHolder::new();
+        for n in 0..4 {
+            let offset = n as f32 * 3.1;
+            for (team_id, x, y) in [(0, 10.5, 10.5), (1, 21.5, 11.2)].iter() {
+                let id = entity_holder.id_counter;
+                entity_holder.entities.insert(
+                    id, Entity::new(*x, *y + offset, id, *team_id, EntityType::Ranged)
+                );
+                entity_holder.id_counter += 1;
+            }
+        }
+
+        GameState {
+            tick: 0,
+            seed: 0,
+            rng: GameRng::new(0),
+            map: Map::new(32, 32),
+            entity_holder: entity_holder,
+            event_queue: VecDeque::new(),
+            recorded_events: None,
+            debug_state_hash_interval: 0,
+        }
+    }
+
+    pub fn simulation_snapshot(game_state: &GameState) -> (u32, Vec<u8>, Vec<Vec<u8>>, Vec<Vec<u8>>) {
+        let entities: Vec<Vec<u8>> = game_state.entity_holder().entities_iter()
+            .map(|entity| entity.as_binary()).collect();
+        let projectiles: Vec<Vec<u8>> = game_state.entity_holder().projectiles.iter()
+            .map(|projectile| projectile.as_binary()).collect();
+        (game_state.tick(), game_state.map().as_binary(), entities, projectiles)
+    }
+
+    pub fn random_game_event(rng: &mut SmallRng) -> GameEvent {
+        match rng.gen_range(0, 4) {
+            0 => GameEvent::OrderUnits {
+                task: random_task(rng),
+                unit_ids: (0..rng.gen_range(0, 10)).map(|_| (rng.gen(), true)).collect(),
+            },
+            1 => GameEvent::InsertUnit {
+                location: random_point(rng),
+                team_id: rng.gen(),
+                unit_type: match rng.gen_range(0, 3) {
+                    0 => EntityType::Peasant,
+                    1 => EntityType::Ranged,
+                    _ => EntityType::Meelee,
+                },
+            },
+            2 => GameEvent::SetMapPoint {
+                location: (rng.gen(), rng.gen()),
+                ground_type: GroundType::from_u8(rng.gen_range(0, 5)).unwrap(),
+            },
+            _ => GameEvent::AddBuilding { location: (rng.gen(), rng.gen()) },
+        }
+    }
+
+    #[test]
+    fn save_header_round_trip() {
+        assert_round_trip(&SaveHeader::new());
+    }
+
+    #[test]
+    fn game_state_round_trip() {
+        for case in 0..PROPERTY_TEST_CASES {
+            let mut rng = property_test_rng(case);
+            let game_state = GameState {
+                tick: rng.gen(),
+                seed: rng.gen(),
+                rng: GameRng::from_state(rng.gen()),
+                map: random_map(&mut rng),
+                entity_holder: random_entity_holder(&mut rng),
+                event_queue: VecDeque::new(),
+                recorded_events: None,
+                debug_state_hash_interval: 0,
+            };
+
+            let (header, decoded) = GameState::from_save_binary(&game_state.as_save_binary()).unwrap();
+
+            assert_eq!(header.format_version, SAVE_FORMAT_VERSION);
+            assert_eq!(decoded.tick(), game_state.tick());
+            assert_eq!(decoded.seed(), game_state.seed());
+            assert_eq!(decoded.rng, game_state.rng);
+            assert_eq!(decoded.map().as_binary(), game_state.map().as_binary());
+            assert_same_entity_holder(decoded.entity_holder(), game_state.entity_holder());
+        }
+    }
+
+    #[test]
+    fn game_event_round_trip() {
+        for case in 0..PROPERTY_TEST_CASES {
+            let mut rng = property_test_rng(case);
+            let scheduled_event = ScheduledEvent { tick: rng.gen(), game_event: random_game_event(&mut rng) };
+            assert_round_trip(&scheduled_event);
+
+            // Non-finite floats have no JSON representation and are written as null
+            let text = ScheduledEvent::list_to_json(&[scheduled_event.clone()]);
+            if text.contains("null") {
+                continue;
+            }
+            let decoded = ScheduledEvent::list_from_json(&text).unwrap();
+            assert_eq!(decoded.len(), 1);
+            assert_eq!(decoded[0].as_binary(), scheduled_event.as_binary());
+        }
+        assert_eq!(GameEvent::from_binary(&[4]).err(), Some(DecodeError::UnknownEventTag(4)));
+    }
+
+    #[test]
+    fn loads_hand_written_event_script() {
+        let scheduled_events = ScheduledEvent::list_from_json(r#"[
+            {"tick": 1, "game_event": {"InsertUnit": {
+                "location": {"x": 4.5, "y": 1.5}, "team_id": 1, "unit_type": "Ranged"
+            }}},
+            {"tick": 1, "game_event": {"SetMapPoint": {"location": [2, 2], "ground_type": "Water"}}},
+            {"tick": 2, "game_event": {"OrderUnits": {
+                "task": {"Move": {"point": {"x": 6.5, "y": 2.5}}}, "unit_ids": [1, 0]
+            }}},
+            {"tick": 3, "game_event": {"AddBuilding": {"location": [6, 0]}}}
+        ]"#).unwrap();
+
+        let mut game_state = single_unit_game_state();
+        for scheduled_event in scheduled_events {
+            game_state.schedule_event(scheduled_event.tick, scheduled_event.game_event);
+        }
+        game_state.do_tick();
+        assert_eq!(game_state.entity_holder().entities.len(), 2);
+        assert_eq!(game_state.map().get_at(2, 2), GroundType::Water);
+        game_state.do_tick();
+        match game_state.entity_holder().entities.get(&1).unwrap().task() {
+            Task::Move { point } => assert_eq!((point.x, point.y), (6.5, 2.5)),
+            _ => panic!("Expected a move task"),
+        }
+        game_state.do_tick();
+        assert_eq!(game_state.entity_holder().buildings.len(), 1);
+    }
+
+    #[test]
+    fn loads_version_0_save() {
+        let (header, game_state) = GameState::from_save_binary(&legacy_game_state_binary()).unwrap();
+        assert_eq!(header.format_version, 0);
+        assert_legacy_game_state(&game_state);
+    }
+
+    #[test]
+    fn loads_version_1_save() {
+        let mut binary_data: Vec<u8> = Vec::new();
+        binary_data.extend(SAVE_FILE_MAGIC.iter());
+        binary_data.extend(vec![0, 0, 0, 1]); // Format version
+        binary_data.extend(vec![0, 0, 0, 5]); // Engine version length
+        binary_data.extend(b"0.1.0".iter());
+        binary_data.extend(vec![0, 0, 0, 0, 0, 0, 1, 0]); // Created at
+        binary_data.extend(legacy_game_state_binary());
+
+        let (header, game_state) = GameState::from_save_binary(&binary_data).unwrap();
+        assert_eq!(header.format_version, 1);
+        assert_eq!(header.engine_version, "0.1.0");
+        assert_eq!(header.created_at, 256);
+        assert_legacy_game_state(&game_state);
+    }
+
+    #[test]
+    fn loads_version_4_save() {
+        // Version 4 game states have no seed or random number generator state
+        let mut game_state = battle_game_state();
+        game_state.tick = 9;
+        let mut header = SaveHeader::new();
+        header.format_version = 4;
+        let mut writer = BinaryWriter::new();
+        header.write_binary(&mut writer);
+        write_legacy_game_state(&mut writer, &game_state, 4);
+        writer.write_checksum();
+
+        let (header, loaded) = GameState::from_save_binary(&writer.into_binary()).unwrap();
+        assert_eq!(header.format_version, 4);
+        assert_eq!(loaded.tick(), 9);
+        assert_eq!(loaded.seed(), 0);
+        assert_eq!(loaded.rng, GameRng::new(0));
+        assert_eq!(loaded.entity_holder().entities.len(), 8);
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_world() {
+        let first = GameState::new_with_seed(42);
+        let second = GameState::new_with_seed(42);
+        assert_eq!(first.seed(), 42);
+        assert_eq!(first.map().as_binary(), second.map().as_binary());
+        assert_eq!(first.rng, second.rng);
+
+        let other = GameState::new_with_seed(43);
+        assert_ne!(first.map().as_binary(), other.map().as_binary());
+    }
+
+    #[test]
+    fn random_number_generator_survives_saving() {
+        let mut game_state = GameState::new_with_seed(7);
+        game_state.rng().next_u64();
+
+        let (_header, mut loaded) = GameState::from_save_binary(&game_state.as_save_binary()).unwrap();
+        let mut from_text = GameState::from_json(&game_state.to_json()).unwrap();
+        assert_eq!(loaded.seed(), 7);
+        assert_eq!(from_text.seed(), 7);
+        let expected = game_state.rng().next_u64();
+        assert_eq!(loaded.rng().next_u64(), expected);
+        assert_eq!(from_text.rng().next_u64(), expected);
+
+        // A scenario giving only the seed starts the generator from it
+        let seeded = GameState::from_json(r#"{"seed": 7, "map": {"ground_layer": ["."]}}"#).unwrap();
+        assert_eq!(seeded.rng, GameRng::new(7));
+    }
+
+    fn single_unit_game_state() -> GameState {
+        GameState::from_json(r#"{
+            "map": {"ground_layer": ["........", "........", "........"]},
+            "entity_holder": {"entities": [
+                {"location": {"x": 1.3, "y": 1.6}, "id": 0, "entity_type": "Ranged", "team_id": 0}
+            ]}
+        }"#).unwrap()
+    }
+
+    #[test]
+    fn events_are_applied_in_the_order_they_were_dispatched() {
+        let mut game_state = single_unit_game_state();
+        game_state.dispatch_event(GameEvent::SetMapPoint { location: (3, 1), ground_type: GroundType::Water });
+        game_state.dispatch_event(GameEvent::SetMapPoint { location: (3, 1), ground_type: GroundType::Sand });
+        game_state.dispatch_event(GameEvent::AddBuilding { location: (5, 0) });
+        game_state.dispatch_event(GameEvent::InsertUnit {
+            location: Point::new(4.5, 2.5), team_id: 1, unit_type: EntityType::Ranged,
+        });
+        game_state.start_recording();
+        game_state.do_tick();
+
+        assert_eq!(game_state.map().get_at(3, 1), GroundType::Sand);
+        let recorded_tags: Vec<(u32, u8)> = game_state.take_recorded_events().iter()
+            .map(|scheduled_event| (scheduled_event.tick, scheduled_event.game_event.as_binary()[0]))
+            .collect();
+        assert_eq!(recorded_tags, vec![(1, 2), (1, 2), (1, 3), (1, 1)]);
+    }
+
+    #[test]
+    fn inserted_units_have_the_requested_type_and_team() {
+        let mut game_state = single_unit_game_state();
+        for (team_id, unit_type) in [(1, EntityType::Peasant), (2, EntityType::Meelee), (1, EntityType::Peasant)].iter() {
+            game_state.dispatch_event(GameEvent::InsertUnit {
+                location: Point::new(5.3, 1.6), team_id: *team_id, unit_type: unit_type.clone(),
+            });
+        }
+        game_state.do_tick();
+
+        let (_header, loaded) = GameState::from_save_binary(&game_state.as_save_binary()).unwrap();
+        let units: Vec<(u32, u32, EntityType)> = loaded.entity_holder().entities_iter()
+            .map(|entity| (entity.id(), entity.team_id(), entity.entity_type().clone()))
+            .collect();
+        assert_eq!(units, vec![
+            (0, 0, EntityType::Ranged),
+            (1, 1, EntityType::Peasant),
+            (2, 2, EntityType::Meelee),
+            (3, 1, EntityType::Peasant),
+        ]);
+    }
+
+    #[test]
+    fn events_are_applied_before_entities_act() {
+        let mut game_state = single_unit_game_state();
+        let mut unit_ids: HashMap<u32, bool> = HashMap::new();
+        unit_ids.insert(0, true);
+        game_state.dispatch_event(GameEvent::OrderUnits {
+            task: Task::Move { point: Point::new(6.5, 1.5) },
+            unit_ids: unit_ids,
+        });
+        game_state.do_tick();
+
+        // The unit already moves on the tick the order is applied
+        let entity = game_state.entity_holder().entities.get(&0).unwrap();
+        assert!(entity.location().x > 1.3);
+    }
+
+    #[test]
+    fn scheduled_events_wait_for_their_tick() {
+        let mut game_state = single_unit_game_state();
+        game_state.schedule_event(3, GameEvent::SetMapPoint { location: (0, 1), ground_type: GroundType::Rock });
+        game_state.schedule_event(2, GameEvent::SetMapPoint { location: (0, 1), ground_type: GroundType::Water });
+        game_state.schedule_event(3, GameEvent::SetMapPoint { location: (1, 1), ground_type: GroundType::Rock });
+
+        game_state.do_tick();
+        assert_eq!(game_state.map().get_at(0, 1), GroundType::Grass);
+        game_state.do_tick();
+        assert_eq!(game_state.map().get_at(0, 1), GroundType::Water);
+        assert_eq!(game_state.map().get_at(1, 1), GroundType::Grass);
+        game_state.do_tick();
+        assert_eq!(game_state.map().get_at(0, 1), GroundType::Rock);
+        assert_eq!(game_state.map().get_at(1, 1), GroundType::Rock);
+
+        // Events scheduled for a tick that already passed are applied on the next one
+        game_state.schedule_event(1, GameEvent::SetMapPoint { location: (0, 1), ground_type: GroundType::Sand });
+        game_state.do_tick();
+        assert_eq!(game_state.map().get_at(0, 1), GroundType::Sand);
+    }
+
+    #[test]
+    fn state_hash_depends_on_the_state_only() {
+        let mut game_state = battle_game_state();
+        for _ in 0..150 {
+            game_state.do_tick();
+        }
+        let hash = game_state.state_hash();
+
+        // Loading inserts the entities in a different order
+        let (_header, loaded) = GameState::from_save_binary(&game_state.as_save_binary()).unwrap();
+        assert_eq!(loaded.state_hash(), hash);
+        assert_eq!(GameState::from_json(&game_state.to_json()).unwrap().state_hash(), hash);
+
+        game_state.entity_holder.entities.get_mut(&3).unwrap().take_hit(1);
+        assert_ne!(game_state.state_hash(), hash);
+
+        let mut game_state = GameState::from_json(&loaded.to_json()).unwrap();
+        game_state.map.set(0, 0, GroundType::Water);
+        assert_ne!(game_state.state_hash(), hash);
+        let mut game_state = GameState::from_json(&loaded.to_json()).unwrap();
+        game_state.rng().next_u64();
+        assert_ne!(game_state.state_hash(), hash);
+    }
+
+    fn crowded_battle_game_state() -> GameState {
+        // Two overlapping grids of units, full of tied distances and collisions
+        let mut entity_holder = EntityHolder::new();
+        for n in 0..24 {
+            let team_id = n % 2;
+            let x = 12.5 + (n % 6) as f32 * 0.5 + team_id as f32;
+            let y = 12.5 + (n / 6) as f32;
+            let entity_type = if n % 3 == 0 { EntityType::Ranged } else { EntityType::Meelee };
+            entity_holder.entities.insert(n, Entity::new(x, y, n, team_id, entity_type));
+        }
+        entity_holder.id_counter = 24;
+
+        let mut game_state = battle_game_state();
+        game_state.entity_holder = entity_holder;
+        game_state
+    }
+
+    #[test]
+    fn same_game_runs_the_same_way_twice() {
+        let mut first = crowded_battle_game_state();
+        let mut second = crowded_battle_game_state();
+        for _ in 0..600 {
+            first.do_tick();
+            second.do_tick();
+            assert_eq!(first.state_hash(), second.state_hash());
+        }
+        assert!(first.entity_holder().entities.len() < 24);
+    }
+
+    #[test]
+    fn loading_mid_battle_continues_the_same_simulation() {
+        let mut uninterrupted = battle_game_state();
+        for _ in 0..150 {
+            uninterrupted.do_tick();
+        }
+        assert!(!uninterrupted.entity_holder().projectiles.is_empty());
+
+        let (_header, mut loaded) = GameState::from_save_binary(&uninterrupted.as_save_binary()).unwrap();
+
+        for _ in 0..1000 {
+            uninterrupted.do_tick();
+            loaded.do_tick();
+            assert_eq!(simulation_snapshot(&loaded), simulation_snapshot(&uninterrupted));
+        }
+        assert!(uninterrupted.entity_holder().entities.len() < 8);
+    }
+
+    #[test]
+    fn text_round_trip() {
+        let mut game_state = battle_game_state();
+        for _ in 0..150 {
+            game_state.do_tick();
+        }
+
+        let decoded = GameState::from_json(&game_state.to_json()).unwrap();
+        assert_eq!(decoded.entity_holder().id_counter, game_state.entity_holder().id_counter);
+        assert_eq!(simulation_snapshot(&decoded), simulation_snapshot(&game_state));
+    }
+
+    #[test]
+    fn loads_hand_written_scenario() {
+        let game_state = GameState::from_json(r#"{
+            "map": {
+                "ground_layer": [
+                    "....~",
+                    "..#.~"
+                ]
+            },
+            "entity_holder": {
+                "entities": [
+                    {"location": {"x": 0.5, "y": 0.5}, "id": 4, "entity_type": "Ranged", "team_id": 0},
+                    {
+                        "location": {"x": 3.5, "y": 1.5}, "id": 7, "entity_type": "Meelee", "team_id": 1,
+                        "task": {"Move": {"point": {"x": 0.5, "y": 1.5}}}
+                    }
+                ],
+                "buildings": [{"x": 1, "y": 0}]
+            }
+        }"#).unwrap();
+
+        assert_eq!(game_state.tick(), 0);
+        assert_eq!(game_state.map().width(), 5);
+        assert_eq!(game_state.map().height(), 2);
+        assert!(game_state.map().get_at(2, 1) == GroundType::Rock);
+        assert!(game_state.map().get_at(4, 0) == GroundType::Water);
+        assert_eq!(game_state.entity_holder().id_counter, 8);
+        assert_eq!(game_state.entity_holder().buildings.len(), 1);
+
+        let entity = game_state.entity_holder().entities.get(&7).unwrap();
+        assert_eq!(entity.hp(), entity.max_hp());
+        match entity.task() {
+            Task::Move { point } => assert_eq!((point.x, point.y), (0.5, 1.5)),
+            _ => panic!("Expected a move task"),
+        }
+    }
+
+    #[test]
+    fn text_errors_are_reported() {
+        let uneven_map = r#"{"map": {"ground_layer": ["...", ".."]}}"#;
+        match GameState::from_json(uneven_map) {
+            Err(DecodeError::InvalidText(message)) => assert!(message.contains("row 1")),
+            _ => panic!("Expected uneven map rows to be an error"),
+        }
+
+        let duplicate_ids = r#"{
+            "map": {"ground_layer": ["."]},
+            "entity_holder": {"entities": [
+                {"location": {"x": 0.5, "y": 0.5}, "id": 1, "entity_type": "Ranged", "team_id": 0},
+                {"location": {"x": 0.5, "y": 0.5}, "id": 1, "entity_type": "Ranged", "team_id": 1}
+            ]}
+        }"#;
+        match GameState::from_json(duplicate_ids) {
+            Err(DecodeError::InvalidText(message)) => assert!(message.contains("duplicate entity id 1")),
+            _ => panic!("Expected duplicate entity ids to be an error"),
+        }
+    }
+
+    #[test]
+    fn rejects_corrupted_save() {
+        let mut binary_data = battle_game_state().as_save_binary();
+        let middle = binary_data.len() / 2;
+        binary_data[middle] ^= 1;
+        match GameState::from_save_binary(&binary_data) {
+            Err(DecodeError::ChecksumMismatch { .. }) => {},
+            _ => panic!("Expected a checksum mismatch"),
+        }
+
+        let binary_data = battle_game_state().as_save_binary();
+        assert!(GameState::from_save_binary(&binary_data[..binary_data.len() - 10]).is_err());
+    }
+
+    #[test]
+    fn rejects_newer_save() {
+        let mut header = SaveHeader::new();
+        header.format_version = SAVE_FORMAT_VERSION + 1;
+        assert_eq!(
+            GameState::from_save_binary(&header.as_binary()).err(),
+            Some(DecodeError::UnsupportedVersion(SAVE_FORMAT_VERSION + 1)),
+        );
+    }
+}
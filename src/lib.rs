@@ -0,0 +1,23 @@
+extern crate multi_mut;
+extern crate byteorder;
+extern crate crc32fast;
+extern crate serde;
+extern crate serde_json;
+
+
+pub mod point;
+pub mod map;
+pub mod path_finder;
+pub mod entity;
+pub mod entity_holder;
+pub mod noise;
+pub mod projectile;
+pub mod building;
+pub mod game_state;
+pub mod binary_helpers;
+pub mod save_slots;
+pub mod game_rng;
+pub mod replay;
+pub mod game_clock;
+pub mod unit_types;
+pub mod cli;
@@ -1,13 +1,14 @@
-extern crate multi_mut;
 extern crate sdl2;
-extern crate byteorder;
+extern crate rust_game;
 
 
 use std::collections::HashMap;
 use std::cmp::Ordering;
 use std::env;
+use std::path::Path;
 
 use sdl2::image::{LoadTexture, InitFlag};
+use sdl2::gfx::primitives::DrawRenderer;
 use sdl2::pixels::Color;
 use sdl2::event::Event;
 use sdl2::keyboard::{Keycode, Scancode};
@@ -19,23 +20,147 @@ use sdl2::video::{WindowContext, Window};
 use std::time::Instant;
 use std::cmp;
 
-mod point;
 mod camera;
-mod map;
-mod path_finder;
-mod entity;
-mod entity_holder;
-mod noise;
 mod texture_holder;
-mod projectile;
-mod building;
-mod game_state;
-mod binary_helpers;
 
-use game_state::{GameState, GameEvent};
+use rust_game::{point, map, entity};
+use rust_game::game_state::{GameState, GameEvent, ScheduledEvent};
+use rust_game::save_slots::{SaveSlots, DEFAULT_SAVE_DIRECTORY, QUICKSAVE_SLOT, EXPORT_SLOT};
+use rust_game::replay::{Replay, ReplayPlayer, SEEK_STEP};
+use rust_game::game_clock::GameClock;
+use rust_game::cli::{parse_number_option, file_option, load_unit_definitions};
+
+
+fn unit_type_for_key(keycode: Keycode) -> Option<entity::EntityType> {
+    match keycode {
+        Keycode::Num1 => Some(entity::EntityType::Peasant),
+        Keycode::Num2 => Some(entity::EntityType::Meelee),
+        Keycode::Num3 => Some(entity::EntityType::Ranged),
+        _ => None,
+    }
+}
+
+fn save_slot_for_key(keycode: Keycode) -> Option<&'static str> {
+    match keycode {
+        Keycode::F1 => Some("slot-1"),
+        Keycode::F2 => Some("slot-2"),
+        Keycode::F3 => Some("slot-3"),
+        Keycode::F4 => Some("slot-4"),
+        _ => None,
+    }
+}
+
+fn save_game(save_slots: &SaveSlots, name: &str, game_state: &GameState) {
+    match save_slots.save(name, game_state) {
+        Ok(path) => println!("Game saved to {}", path.display()),
+        Err(error) => println!("Saving to slot {} failed: {}", name, error),
+    }
+}
+
+fn load_game(save_slots: &SaveSlots, name: &str, game_state: &mut GameState) -> bool {
+    // Replaces the running game state, keeping it if loading fails
+    match save_slots.load(name) {
+        Ok(loaded_game_state) => {
+            println!("Game loaded from slot {}", name);
+            let mut loaded_game_state = loaded_game_state;
+            loaded_game_state.debug_state_hash_interval = game_state.debug_state_hash_interval;
+            *game_state = loaded_game_state;
+            true
+        },
+        Err(error) => {
+            println!("Loading slot {} failed: {}", name, error);
+            false
+        }
+    }
+}
+
+fn after_load(
+    game_state: &mut GameState,
+    selected_entity_ids: &mut HashMap<u32, bool>,
+    previous_locations: &mut HashMap<u32, point::Point>,
+    replay_player: &mut Option<ReplayPlayer>,
+    recording: &mut Option<Replay>,
+) {
+    // Forgets everything that referred to the game state before the load
+    selected_entity_ids.clear();
+    previous_locations.clear();
+    *replay_player = None;
+    if recording.is_some() {
+        println!("Replay recording restarted from the loaded game");
+        *recording = Some(Replay::record(game_state));
+    }
+}
+
+fn save_replay(replay: &mut Replay, game_state: &mut GameState, file_name: &str) {
+    replay.update(game_state);
+    match replay.save_to_file(file_name) {
+        Ok(()) => println!("Replay of ticks {}-{} saved to {}", replay.start_tick(), replay.end_tick(), file_name),
+        Err(error) => println!("Saving replay to {} failed: {}", file_name, error),
+    }
+}
+
+fn handle_playback_key(player: &mut ReplayPlayer, game_state: &mut GameState, keycode: Keycode) -> bool {
+    // Returns true if the key controlled the playback
+    match keycode {
+        Keycode::Space => player.paused = !player.paused,
+        Keycode::F => player.toggle_fast_forward(),
+        Keycode::Left => {
+            let tick = game_state.tick().saturating_sub(SEEK_STEP);
+            player.seek(game_state, tick);
+        },
+        Keycode::Right => {
+            let tick = game_state.tick() + SEEK_STEP;
+            player.seek(game_state, tick);
+        },
+        Keycode::Home => player.restart(game_state),
+        _ => return false,
+    }
+    true
+}
 
 
 pub fn main() -> Result<(), String> {
+    // Usage: rust-game [--save-dir DIR] [--autosave-interval TICKS] [--autosave-count N] [--seed SEED]
+    //                  [--record REPLAY_FILE] [--replay REPLAY_FILE] [--script EVENT_FILE]
+    //                  [--hash-interval TICKS] [--units UNIT_FILE] [FILE or SLOT]
+    let mut save_directory = DEFAULT_SAVE_DIRECTORY.to_string();
+    let mut autosave_interval: Option<u32> = None;
+    let mut autosave_count: Option<u32> = None;
+    let mut seed: Option<u64> = None;
+    let mut record_file: Option<String> = None;
+    let mut replay_file: Option<String> = None;
+    let mut script_file: Option<String> = None;
+    let mut hash_interval: u32 = 0;
+    let mut unit_file: Option<String> = None;
+    let mut load_argument: Option<String> = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(argument) = args.next() {
+        match argument.as_str() {
+            "--save-dir" => save_directory = args.next().ok_or("--save-dir needs a directory")?,
+            "--autosave-interval" => autosave_interval = Some(parse_number_option(&argument, args.next())?),
+            "--autosave-count" => autosave_count = Some(parse_number_option(&argument, args.next())?),
+            "--seed" => seed = Some(parse_number_option(&argument, args.next())?),
+            "--record" => record_file = Some(file_option(&argument, args.next())?),
+            "--replay" => replay_file = Some(file_option(&argument, args.next())?),
+            "--script" => script_file = Some(file_option(&argument, args.next())?),
+            "--hash-interval" => hash_interval = parse_number_option(&argument, args.next())?,
+            "--units" => unit_file = Some(file_option(&argument, args.next())?),
+            _ => load_argument = Some(argument),
+        }
+    }
+
+    // Unit stats are looked up from here on, so they are loaded first
+    load_unit_definitions(unit_file)?;
+
+    let mut save_slots = SaveSlots::new(&save_directory);
+    if let Some(interval) = autosave_interval { save_slots.autosave_interval = interval; }
+    if let Some(count) = autosave_count { save_slots.autosave_count = count; }
+    println!(
+        "Save slots in {}: {}",
+        save_slots.directory().display(),
+        save_slots.list().join(", "),
+    );
 
     let sdl_context = sdl2::init()?;
     let video_subsystem = sdl_context.video()?;
@@ -66,22 +191,65 @@ pub fn main() -> Result<(), String> {
 
     let mut camera: camera::Camera = camera::Camera::new(600, 600);
 
-    let args: Vec<String> = env::args().collect();
-
-    let mut game_state = match args.get(1) {
-        Some(argument) => {
-            println!("Loading game state from file: {}", argument);
-            GameState::from_file_name(argument.to_string())
+    let mut replay_player: Option<ReplayPlayer> = None;
+    let mut game_state = match (replay_file, load_argument) {
+        (Some(file_name), _) => {
+            println!("Playing replay: {}", file_name);
+            let replay = Replay::load_file(&file_name)
+                .map_err(|e| format!("Loading replay failed: {}", e))?;
+            if !replay.matches_unit_definitions() {
+                println!("Warning: the replay was recorded with other unit definitions and will not play out the same");
+            }
+            let player = ReplayPlayer::new(replay);
+            let game_state = player.replay().initial_state();
+            replay_player = Some(player);
+            game_state
+        },
+        (None, Some(argument)) => {
+            if !Path::new(&argument).exists() && save_slots.exists(&argument) {
+                println!("Loading game state from save slot: {}", argument);
+                save_slots.load(&argument)
+            } else {
+                println!("Loading game state from file: {}", argument);
+                GameState::from_file_name(argument.to_string())
+            }.map_err(|e| format!("Loading game state failed: {}", e))?
         },
-        None => {
-            println!("Initializing new game state");
-            GameState::new()
+        (None, None) => {
+            let game_state = match seed {
+                Some(seed) => GameState::new_with_seed(seed),
+                None => GameState::new(),
+            };
+            println!("Initializing new game state with seed {}", game_state.seed());
+            game_state
         },
     };
 
+    game_state.debug_state_hash_interval = hash_interval;
+
+    if let Some(file_name) = script_file {
+        let text = std::fs::read_to_string(&file_name)
+            .map_err(|e| format!("Reading event script {} failed: {}", file_name, e))?;
+        let scheduled_events = ScheduledEvent::list_from_json(&text)
+            .map_err(|e| format!("Loading event script failed: {}", e))?;
+        println!("Scheduled {} events from {}", scheduled_events.len(), file_name);
+        for scheduled_event in scheduled_events {
+            game_state.schedule_event(scheduled_event.tick, scheduled_event.game_event);
+        }
+    }
+
+    // Records every applied event so the game can be played back later
+    let mut recording: Option<Replay> = match (&record_file, &replay_player) {
+        (Some(_), None) => Some(Replay::record(&mut game_state)),
+        _ => None,
+    };
+
     let start_time = Instant::now();
     let mut last_time = start_time.elapsed();
-    let mut elapsed_time = 1_000_000_000;
+    let mut elapsed_time = 1_000_000_000 / 60;
+
+    let mut game_clock = GameClock::new();
+    // Entity locations before the last simulated tick, drawn interpolated
+    let mut previous_locations: HashMap<u32, point::Point> = HashMap::new();
 
     let mut left_pressed: bool = false;
     let mut mouse_start_game_pos: (f32, f32) = (0.0, 0.0);
@@ -92,6 +260,9 @@ pub fn main() -> Result<(), String> {
 
     let mut selected_entity_ids: HashMap<u32, bool> = HashMap::new();
 
+    // Type of the units added with N and M
+    let mut spawn_unit_type = entity::EntityType::Ranged;
+
     // return Ok(());
 
     loop {
@@ -108,6 +279,8 @@ pub fn main() -> Result<(), String> {
 
         let mut attack_move = false;
         if keyboard_state.is_scancode_pressed(Scancode::Q) {attack_move = true};
+        let shift_pressed = keyboard_state.is_scancode_pressed(Scancode::LShift)
+            || keyboard_state.is_scancode_pressed(Scancode::RShift);
 
         if mouse_state.left() {
             if left_pressed == false {
@@ -129,12 +302,29 @@ pub fn main() -> Result<(), String> {
         }
 
         for event in event_pump.poll_iter() {
+            if let (Some(player), Event::KeyDown { keycode: Some(keycode), .. }) = (replay_player.as_mut(), &event) {
+                if handle_playback_key(player, &mut game_state, *keycode) {
+                    previous_locations.clear();
+                    continue;
+                }
+            }
             match event {
-                Event::Quit { .. } => return Ok(()),
-                Event::KeyDown { keycode: Some(Keycode::Escape), .. } => return Ok(()),
+                Event::Quit { .. } |
+                Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
+                    if let (Some(replay), Some(file_name)) = (recording.as_mut(), &record_file) {
+                        save_replay(replay, &mut game_state, file_name);
+                    }
+                    return Ok(());
+                },
                 Event::KeyDown { keycode: Some(Keycode::I), .. } => (camera.zoom_in()),
                 Event::KeyDown { keycode: Some(Keycode::O), .. } => (camera.zoom_out()),
                 Event::KeyDown { keycode: Some(Keycode::P), .. } => {debug_enabled = !debug_enabled;},
+                Event::KeyDown { keycode: Some(Keycode::Space), .. } => {game_clock.paused = !game_clock.paused;},
+                Event::KeyDown { keycode: Some(Keycode::Minus), .. } |
+                Event::KeyDown { keycode: Some(Keycode::KpMinus), .. } => {game_clock.slower();},
+                Event::KeyDown { keycode: Some(Keycode::Equals), .. } |
+                Event::KeyDown { keycode: Some(Keycode::Plus), .. } |
+                Event::KeyDown { keycode: Some(Keycode::KpPlus), .. } => {game_clock.faster();},
                 Event::MouseWheel { .. } => {
                     println!("Scroll happened");
                 },
@@ -170,24 +360,61 @@ pub fn main() -> Result<(), String> {
                     game_state.dispatch_event(GameEvent::InsertUnit{
                         location: mouse_game_point.clone(),
                         team_id: 0,
-                        unit_type: entity::EntityType::Ranged,
+                        unit_type: spawn_unit_type.clone(),
                     });
                 },
                 Event::KeyDown { keycode: Some(Keycode::M), .. } => {
                     game_state.dispatch_event(GameEvent::InsertUnit{
                         location: mouse_game_point.clone(),
                         team_id: 1,
-                        unit_type: entity::EntityType::Ranged,
+                        unit_type: spawn_unit_type.clone(),
                     });
                 },
+                Event::KeyDown { keycode: Some(keycode), .. } if unit_type_for_key(keycode).is_some() => {
+                    spawn_unit_type = unit_type_for_key(keycode).unwrap();
+                },
                 Event::KeyDown { keycode: Some(Keycode::X), .. } => {
                     game_state.dispatch_event(GameEvent::OrderUnits{
                         task: entity::Task::Idle,
                         unit_ids: selected_entity_ids.clone(),
                     })
                 },
-                Event::KeyDown { keycode: Some(Keycode::T), .. } => {
-                    game_state.save_to_file();
+                Event::KeyDown { keycode: Some(Keycode::T), .. } |
+                Event::KeyDown { keycode: Some(Keycode::F5), .. } => {
+                    save_game(&save_slots, QUICKSAVE_SLOT, &game_state);
+                },
+                Event::KeyDown { keycode: Some(Keycode::F9), .. } => {
+                    if load_game(&save_slots, QUICKSAVE_SLOT, &mut game_state) {
+                        after_load(
+                            &mut game_state,
+                            &mut selected_entity_ids,
+                            &mut previous_locations,
+                            &mut replay_player,
+                            &mut recording,
+                        );
+                    }
+                },
+                Event::KeyDown { keycode: Some(Keycode::Y), .. } => {
+                    match save_slots.export_text(EXPORT_SLOT, &game_state) {
+                        Ok(path) => println!("Game exported to {}", path.display()),
+                        Err(error) => println!("Exporting failed: {}", error),
+                    }
+                },
+                Event::KeyDown { keycode: Some(keycode), .. } if save_slot_for_key(keycode).is_some() => {
+                    let name = save_slot_for_key(keycode).unwrap();
+                    if shift_pressed {
+                        if load_game(&save_slots, name, &mut game_state) {
+                            after_load(
+                                &mut game_state,
+                                &mut selected_entity_ids,
+                                &mut previous_locations,
+                                &mut replay_player,
+                                &mut recording,
+                            );
+                        }
+                    } else {
+                        save_game(&save_slots, name, &game_state);
+                    }
                 },
                 _ => {}
             }
@@ -198,19 +425,57 @@ pub fn main() -> Result<(), String> {
             let window_size = mut_window.size();
             camera.update_window_size(window_size.0, window_size.1);
             if tick % 10 == 0 {
+                let replay_status = match &replay_player {
+                    Some(player) => format!(
+                        ", replay: {}/{}{} x{}",
+                        game_state.tick(),
+                        player.replay().end_tick(),
+                        if player.paused { " paused" } else { "" },
+                        player.speed(),
+                    ),
+                    None => String::new(),
+                };
+                let speed_status = if game_clock.paused {
+                    "paused".to_string()
+                } else {
+                    format!("{}x", game_clock.speed())
+                };
                 let title = format!(
-                    "Oskun peli, tick: {}, fps: {}, entities: {}, entity_interactions: {}",
-                    tick,
+                    "Oskun peli, tick: {}, speed: {}, adding: {:?}, fps: {}, entities: {}, entity_interactions: {}{}",
+                    game_state.tick(),
+                    speed_status,
+                    spawn_unit_type,
                     (1.0 / (elapsed_time as f32 / 1000000000.0)) as i32,
                     game_state.entity_holder().entities_iter().len(),
                     game_state.entity_holder().debug_entity_interaction_count,
+                    replay_status,
                 );
                 mut_window.set_title(&title).map_err(|e| e.to_string())?;
             }
         }
 
-        // Game handling
-        game_state.do_tick();
+        // Game handling, at a fixed tick rate however fast frames are drawn
+        for _ in 0..game_clock.advance(elapsed_time as f32 / 1_000_000_000.0) {
+            previous_locations.clear();
+            for entity in game_state.entity_holder().entities_iter() {
+                previous_locations.insert(entity.id(), *entity.location());
+            }
+            match replay_player.as_mut() {
+                Some(player) => player.update(&mut game_state),
+                None => {
+                    game_state.do_tick();
+                    match save_slots.autosave(&game_state) {
+                        Ok(Some(name)) => println!("Autosaved to slot {}", name),
+                        Ok(None) => {},
+                        Err(error) => println!("Autosave failed: {}", error),
+                    }
+                },
+            }
+        }
+        let interpolation = game_clock.interpolation();
+        if let Some(replay) = recording.as_mut() {
+            replay.update(&mut game_state);
+        }
 
 
         { // Draw
@@ -295,7 +560,10 @@ pub fn main() -> Result<(), String> {
             for entity in entity_refs {
                 canvas.set_draw_color(Color::RGB(0, 0, 255));
 
-                let entity_location = entity.location();
+                let entity_location = match previous_locations.get(&entity.id()) {
+                    Some(previous_location) => previous_location.interpolated(entity.location(), interpolation),
+                    None => *entity.location(),
+                };
                 let screen_center_pos = camera.game_to_screen(entity_location.x, entity_location.y);
 
                 let rect = Rect::new(
@@ -311,11 +579,7 @@ pub fn main() -> Result<(), String> {
                     unit_tile_size * 2,
                 );
                 canvas.copy(&shadow_texture, None, rect).map_err(|e| e.to_string())?;
-                let entity_type_id: u32 = match entity.entity_type() {
-                    entity::EntityType::Peasant => 0,
-                    entity::EntityType::Meelee => 1,
-                    entity::EntityType::Ranged => 2,
-                };
+                let entity_type_id: u32 = entity.stats().sprite_row;
                 canvas.copy(
                     texture_holder.get_team_texture((entity.team_id()) as usize)?,
                     Rect::new(
@@ -404,7 +668,8 @@ pub fn main() -> Result<(), String> {
 
             // Draw projectiles
             for projectile in game_state.entity_holder().projectiles.iter() {
-                let screen_center_pos = camera.game_to_screen(projectile.location().x, projectile.location().y);
+                let projectile_location = projectile.previous_location().interpolated(projectile.location(), interpolation);
+                let screen_center_pos = camera.game_to_screen(projectile_location.x, projectile_location.y);
                 let shadow_rect = Rect::new(
                     (screen_center_pos.0 - 1.0 * 32.0 / camera.zoom) as i32,
                     (screen_center_pos.1 - 1.0 * 32.0 / camera.zoom) as i32,
@@ -415,8 +680,7 @@ pub fn main() -> Result<(), String> {
                     (screen_center_pos.0 - 1.0 * 32.0 / camera.zoom) as i32,
                     (
                         screen_center_pos.1 - 1.0 * 32.0 / camera.zoom -
-                        camera.get_tile_size() as f32 * 0.5 - // Throw should not start from ground
-                        camera.get_tile_size() as f32 * projectile.get_height() * 0.2 // Parabel
+                        camera.get_tile_size() as f32 * projectile.height()
                     ) as i32,
                     unit_tile_size,
                     unit_tile_size,
@@ -459,6 +723,21 @@ pub fn main() -> Result<(), String> {
                 ))?;
             }
 
+            // Draw time spent in each simulation phase on the latest tick
+            if debug_enabled {
+                let phases = game_state.entity_holder().debug_phase_timings.phases();
+                canvas.set_draw_color(Color::RGB(0, 0, 0));
+                canvas.fill_rect(Rect::new(5, 5, 370, phases.len() as u32 * 14 + 10))?;
+                for (index, (name, duration)) in phases.iter().enumerate() {
+                    let y = 10 + index as i32 * 14;
+                    let milliseconds = duration.as_secs_f64() * 1000.0;
+                    canvas.string(10, y as i16, &format!("{:>16} {:>7.3} ms", name, milliseconds), Color::RGB(255, 255, 255))?;
+                    // 10 pixels per millisecond, a 60 Hz tick has 16.7 ms in total
+                    canvas.set_draw_color(Color::RGB(255, 160, 0));
+                    canvas.fill_rect(Rect::new(210, y, (milliseconds * 10.0).min(160.0) as u32 + 1, 8))?;
+                }
+            }
+
             canvas.present();
         }
 
@@ -1,10 +1,13 @@
 use std::mem::transmute;
 use super::point;
 use super::noise;
-use super::binary_helpers::Binaryable;
-use super::binary_helpers;
+use super::game_rng::GameRng;
+use super::binary_helpers::{Binaryable, BinaryReader, BinaryWriter, DecodeError};
 
-#[derive(Copy, Clone, PartialEq)]
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de;
+
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
 pub enum GroundType {
     Empty,
 
@@ -17,7 +20,7 @@ pub enum GroundType {
     // CutTrees,
 }
 
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum SecondLevelType {
     Empty,
 
@@ -27,6 +30,93 @@ pub enum SecondLevelType {
     CutTree,
 }
 
+impl GroundType {
+    // Characters used for the map in the text format and ascii views
+    pub fn as_char(&self) -> char {
+        match self {
+            GroundType::Empty => 'X',
+            GroundType::Grass => '.',
+            GroundType::Water => '~',
+            GroundType::Sand => ':',
+            GroundType::Rock => '#',
+        }
+    }
+
+    pub fn from_char(tile: char) -> Option<GroundType> {
+        match tile {
+            'X' => Some(GroundType::Empty),
+            '.' => Some(GroundType::Grass),
+            '~' => Some(GroundType::Water),
+            ':' => Some(GroundType::Sand),
+            '#' => Some(GroundType::Rock),
+            _ => None,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Result<GroundType, DecodeError> {
+        match value {
+            0 => Ok(GroundType::Empty),
+            1 => Ok(GroundType::Grass),
+            2 => Ok(GroundType::Water),
+            3 => Ok(GroundType::Sand),
+            4 => Ok(GroundType::Rock),
+            _ => Err(DecodeError::UnknownGroundType(value)),
+        }
+    }
+}
+
+
+impl Binaryable for GroundType {
+    fn write_binary(&self, writer: &mut BinaryWriter) {
+        writer.write_u8(*self as u8);
+    }
+
+    fn read_binary(reader: &mut BinaryReader) -> Result<GroundType, DecodeError> {
+        GroundType::from_u8(reader.read_u8()?)
+    }
+}
+
+
+impl SecondLevelType {
+    pub fn as_char(&self) -> char {
+        match self {
+            SecondLevelType::Empty => '.',
+            SecondLevelType::Building => 'B',
+            SecondLevelType::Tree => 't',
+            SecondLevelType::CutTree => ',',
+        }
+    }
+
+    // Projectiles flying lower than this over the tile are blocked, in tiles
+    pub fn obstacle_height(&self) -> f32 {
+        match self {
+            SecondLevelType::Tree => 1.2,
+            SecondLevelType::Building => 1.0,
+            _ => 0.0,
+        }
+    }
+
+    pub fn from_char(tile: char) -> Option<SecondLevelType> {
+        match tile {
+            '.' => Some(SecondLevelType::Empty),
+            'B' => Some(SecondLevelType::Building),
+            't' => Some(SecondLevelType::Tree),
+            ',' => Some(SecondLevelType::CutTree),
+            _ => None,
+        }
+    }
+}
+
+
+// Text representation of Map: one string per row, one character per tile
+#[derive(Serialize, Deserialize)]
+struct MapText {
+    ground_layer: Vec<String>,
+    #[serde(default)]
+    second_layer: Vec<String>,
+}
+
+
 pub struct Map {
     height: u32,
     width: u32,
@@ -35,72 +125,180 @@ pub struct Map {
 }
 
 
-impl Binaryable for Map {
-    fn as_binary(&self) -> Vec<u8> {
-        let mut binary_data: Vec<u8> = Vec::new();
+// Saves before this version stored both map layers raw without an encoding byte
+const MAP_LAYER_ENCODING_SINCE_VERSION: u32 = 4;
+
+// Larger maps in a save are rejected before anything is allocated for them
+const MAX_MAP_TILES: u32 = 4096 * 4096;
 
-        binary_data.extend(binary_helpers::u32_as_bytes(self.width));
-        binary_data.extend(binary_helpers::u32_as_bytes(self.height));
+const RAW_LAYERS: u8 = 0;
+const RUN_LENGTH_LAYERS: u8 = 1;
 
-        let map_data: Vec<u8> = self.data.iter().map(|i| *i as u8).collect();
-        binary_data.extend(map_data);
-        let map_data: Vec<u8> = self.second_level_data.iter().map(|i| *i as u8).collect();
-        binary_data.extend(map_data);
 
-        binary_data
+fn run_length_encode(layer: &[u8]) -> Vec<u8> {
+    // Runs of u32 count followed by u8 value. Runs continue over row ends.
+    let mut writer = BinaryWriter::new();
+    let mut index = 0;
+    while index < layer.len() {
+        let value = layer[index];
+        let mut count = 1;
+        while index + count < layer.len() && layer[index + count] == value {
+            count += 1;
+        }
+        writer.write_u32(count as u32);
+        writer.write_u8(value);
+        index += count;
     }
+    writer.into_binary()
+}
 
-    fn from_binary(binary_data: Vec<u8>) -> Map {
-        let (width, binary_data) = binary_helpers::pop_u32(binary_data);
-        let (height, binary_data) = binary_helpers::pop_u32(binary_data);
 
-        let data_size = width * height;
-        let mut new_map = Map::new(width, height);
+fn run_length_decode(reader: &mut BinaryReader, data_size: usize) -> Result<Vec<u8>, DecodeError> {
+    let mut layer: Vec<u8> = Vec::new();
+    while !reader.is_empty() {
+        let count = reader.read_u32()? as usize;
+        let value = reader.read_u8()?;
+        if layer.len() + count > data_size {
+            return Err(DecodeError::LengthMismatch {
+                expected: data_size,
+                actual: layer.len() + count,
+            });
+        }
+        layer.resize(layer.len() + count, value);
+    }
+    if layer.len() != data_size {
+        return Err(DecodeError::LengthMismatch {
+            expected: data_size,
+            actual: layer.len(),
+        });
+    }
+    Ok(layer)
+}
+
 
-        let (first_level_data, second_level_data) = binary_helpers::pop_bytes_from_vec(binary_data, data_size);
+impl Binaryable for Map {
+    fn write_binary(&self, writer: &mut BinaryWriter) {
+        writer.write_u32(self.width);
+        writer.write_u32(self.height);
+
+        let ground_layer: Vec<u8> = self.data.iter().map(|i| *i as u8).collect();
+        let second_layer: Vec<u8> = self.second_level_data.iter().map(|i| *i as u8).collect();
+
+        // Run-length encoding is used whenever it is smaller, which it is for
+        // all but the smallest or noisiest maps
+        let ground_runs = run_length_encode(&ground_layer);
+        let second_runs = run_length_encode(&second_layer);
+        if ground_runs.len() + second_runs.len() + 8 < ground_layer.len() + second_layer.len() {
+            writer.write_u8(RUN_LENGTH_LAYERS);
+            writer.write_padded_bytes(&ground_runs);
+            writer.write_padded_bytes(&second_runs);
+        } else {
+            writer.write_u8(RAW_LAYERS);
+            writer.write_bytes(&ground_layer);
+            writer.write_bytes(&second_layer);
+        }
+    }
 
+    fn read_binary(reader: &mut BinaryReader) -> Result<Map, DecodeError> {
+        let width = reader.read_u32()?;
+        let height = reader.read_u32()?;
+
+        let data_size = match width.checked_mul(height) {
+            Some(data_size) if data_size <= MAX_MAP_TILES => data_size as usize,
+            _ => return Err(DecodeError::MapTooLarge { width: width, height: height }),
+        };
+
+        let encoding = if reader.format_version() < MAP_LAYER_ENCODING_SINCE_VERSION {
+            RAW_LAYERS
+        } else {
+            reader.read_u8()?
+        };
+        let (first_level_data, second_level_data) = match encoding {
+            RAW_LAYERS => {
+                if reader.remaining() != data_size * 2 {
+                    return Err(DecodeError::LengthMismatch {
+                        expected: data_size * 2,
+                        actual: reader.remaining(),
+                    });
+                }
+                (reader.read_bytes(data_size)?.to_vec(), reader.read_bytes(data_size)?.to_vec())
+            },
+            RUN_LENGTH_LAYERS => {
+                let first_level_data = run_length_decode(&mut reader.read_padded()?, data_size)?;
+                let second_level_data = run_length_decode(&mut reader.read_padded()?, data_size)?;
+                (first_level_data, second_level_data)
+            },
+            _ => return Err(DecodeError::UnknownMapEncoding(encoding)),
+        };
+
+        let mut new_map = Map::new(width, height);
         for n in 0..data_size {
-            new_map.data[n as usize] = match first_level_data.get(n as usize) {
-                Some(value) => {
-                    match value {
-                        0 => GroundType::Empty,
-                        1 => GroundType::Grass,
-                        2 => GroundType::Water,
-                        3 => GroundType::Sand,
-                        4 => GroundType::Rock,
-                        _ => {
-                            println!("Unhandled data in map loading");
-                            GroundType::Empty
-                        },
-                    }
-                    // GroundType::Grass
-                },
-                _ => {
-                    println!("This should not happen");
-                    GroundType::Empty
-                },
-            };
-            new_map.second_level_data[n as usize] = match second_level_data.get(n as usize) {
-                Some(value) => {
-                    match value {
-                        0 => SecondLevelType::Empty,
-                        1 => SecondLevelType::Building,
-                        2 => SecondLevelType::Tree,
-                        3 => SecondLevelType::CutTree,
-                        _ => {
-                            println!("Unhandled data in map loading");
-                            SecondLevelType::Empty
-                        },
-                    }
-                },
-                _ => {
-                    println!("This should not happen");
-                    SecondLevelType::Empty
-                },
+            new_map.data[n] = GroundType::from_u8(first_level_data[n])?;
+            new_map.second_level_data[n] = match second_level_data[n] {
+                0 => SecondLevelType::Empty,
+                1 => SecondLevelType::Building,
+                2 => SecondLevelType::Tree,
+                3 => SecondLevelType::CutTree,
+                value => return Err(DecodeError::UnknownSecondLevelType(value)),
             };
         }
 
-        new_map
+        Ok(new_map)
+    }
+}
+
+
+impl Serialize for Map {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut ground_layer: Vec<String> = Vec::new();
+        let mut second_layer: Vec<String> = Vec::new();
+        for y in 0..self.height as i32 {
+            ground_layer.push((0..self.width as i32).map(|x| self.get_at(x, y).as_char()).collect());
+            second_layer.push((0..self.width as i32).map(|x| self.get_at_second_level(x, y).as_char()).collect());
+        }
+        MapText {ground_layer: ground_layer, second_layer: second_layer}.serialize(serializer)
+    }
+}
+
+
+impl<'de> Deserialize<'de> for Map {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Map, D::Error> {
+        // The second layer may be left out, in which case it is empty
+        let map_text = MapText::deserialize(deserializer)?;
+
+        let height = map_text.ground_layer.len();
+        let width = map_text.ground_layer.first().map_or(0, |row| row.chars().count());
+        if !map_text.second_layer.is_empty() && map_text.second_layer.len() != height {
+            return Err(de::Error::custom(format!(
+                "second layer has {} rows, ground layer has {}", map_text.second_layer.len(), height
+            )));
+        }
+
+        let mut new_map = Map::new(width as u32, height as u32);
+        for (y, row) in map_text.ground_layer.iter().enumerate() {
+            if row.chars().count() != width {
+                return Err(de::Error::custom(format!("ground layer row {} is not {} tiles wide", y, width)));
+            }
+            for (x, tile) in row.chars().enumerate() {
+                let ground_type = GroundType::from_char(tile).ok_or_else(|| {
+                    de::Error::custom(format!("unknown ground layer tile '{}' at ({}, {})", tile, x, y))
+                })?;
+                new_map.set(x as i32, y as i32, ground_type);
+            }
+        }
+        for (y, row) in map_text.second_layer.iter().enumerate() {
+            if row.chars().count() != width {
+                return Err(de::Error::custom(format!("second layer row {} is not {} tiles wide", y, width)));
+            }
+            for (x, tile) in row.chars().enumerate() {
+                let second_level_type = SecondLevelType::from_char(tile).ok_or_else(|| {
+                    de::Error::custom(format!("unknown second layer tile '{}' at ({}, {})", tile, x, y))
+                })?;
+                new_map.set_second_layer(x as i32, y as i32, second_level_type);
+            }
+        }
+
+        Ok(new_map)
     }
 }
 
@@ -116,12 +314,12 @@ impl Map {
         }
     }
 
-    pub fn new_random(width: u32, height: u32) -> Map {
+    pub fn new_random(width: u32, height: u32, rng: &mut GameRng) -> Map {
         let data_size = width * height;
         let mut new_map = Map::new(width, height);
 
-        let mut height_noise = noise::ComplexNoise::new(4);
-        let mut tree_noise = noise::ComplexNoise::new(3);
+        let mut height_noise = noise::ComplexNoise::new(4, rng);
+        let mut tree_noise = noise::ComplexNoise::new(3, rng);
 
         for n in 0..data_size {
             let x = (n % width) as i32;
@@ -295,3 +493,141 @@ impl Map {
     }
 }
 
+
+#[cfg(test)]
+pub mod tests {
+    use rand::Rng;
+    use rand::rngs::SmallRng;
+
+    use super::*;
+    use super::super::binary_helpers::tests::{assert_round_trip, property_test_rng, PROPERTY_TEST_CASES};
+
+    pub fn random_map(rng: &mut SmallRng) -> Map {
+        let ground_types = [
+            GroundType::Empty, GroundType::Grass, GroundType::Water, GroundType::Sand, GroundType::Rock,
+        ];
+        let second_level_types = [
+            SecondLevelType::Empty, SecondLevelType::Building, SecondLevelType::Tree, SecondLevelType::CutTree,
+        ];
+
+        let mut map = Map::new(rng.gen_range(0, 20), rng.gen_range(0, 20));
+        for x in 0..map.width() as i32 {
+            for y in 0..map.height() as i32 {
+                map.set(x, y, ground_types[rng.gen_range(0, ground_types.len())]);
+                map.set_second_layer(x, y, second_level_types[rng.gen_range(0, second_level_types.len())]);
+            }
+        }
+        map
+    }
+
+    #[test]
+    fn map_round_trip() {
+        for case in 0..PROPERTY_TEST_CASES {
+            assert_round_trip(&random_map(&mut property_test_rng(case)));
+        }
+    }
+
+    #[test]
+    fn map_text_round_trip() {
+        for case in 0..PROPERTY_TEST_CASES {
+            let map = random_map(&mut property_test_rng(case));
+            if map.height() == 0 {
+                // Without rows the text format has no width, so it loads as 0x0
+                continue;
+            }
+            let decoded: Map = serde_json::from_str(&serde_json::to_string(&map).unwrap()).unwrap();
+            assert_eq!(decoded.as_binary(), map.as_binary());
+        }
+    }
+
+    #[test]
+    fn ground_type_round_trip() {
+        let ground_types = [GroundType::Empty, GroundType::Grass, GroundType::Water, GroundType::Sand, GroundType::Rock];
+        for ground_type in ground_types.iter() {
+            assert_round_trip(ground_type);
+            let text = serde_json::to_string(ground_type).unwrap();
+            assert_eq!(serde_json::from_str::<GroundType>(&text).unwrap(), *ground_type);
+        }
+        assert_eq!(GroundType::from_binary(&[5]).err(), Some(DecodeError::UnknownGroundType(5)));
+    }
+
+    #[test]
+    fn map_data_length_must_match_size() {
+        let mut binary_data = Map::new(3, 2).as_binary();
+        binary_data.pop();
+        assert_eq!(
+            Map::from_binary(&binary_data).err(),
+            Some(DecodeError::LengthMismatch { expected: 12, actual: 11 }),
+        );
+    }
+
+    #[test]
+    fn oversized_maps_are_rejected() {
+        // Declares more tiles than fit in a u32, covered by a few huge runs
+        let mut runs = BinaryWriter::new();
+        for _ in 0..3 {
+            runs.write_u32(u32::MAX);
+            runs.write_u8(1);
+        }
+        let runs = runs.into_binary();
+        let mut writer = BinaryWriter::new();
+        writer.write_u32(65536);
+        writer.write_u32(65537);
+        writer.write_u8(RUN_LENGTH_LAYERS);
+        writer.write_padded_bytes(&runs);
+        writer.write_padded_bytes(&runs);
+        assert_eq!(
+            Map::from_binary(&writer.into_binary()).err(),
+            Some(DecodeError::MapTooLarge { width: 65536, height: 65537 }),
+        );
+
+        let mut writer = BinaryWriter::new();
+        writer.write_u32(5000);
+        writer.write_u32(5000);
+        writer.write_u8(RAW_LAYERS);
+        assert_eq!(
+            Map::from_binary(&writer.into_binary()).err(),
+            Some(DecodeError::MapTooLarge { width: 5000, height: 5000 }),
+        );
+    }
+
+    #[test]
+    fn large_maps_are_run_length_encoded() {
+        let mut map = Map::new(100, 50);
+        for x in 20..40 {
+            map.set(x, 10, GroundType::Water);
+            map.set_second_layer(x, 30, SecondLevelType::Tree);
+        }
+        let binary_data = map.as_binary();
+        assert_eq!(binary_data[8], RUN_LENGTH_LAYERS);
+        assert!(binary_data.len() < 100);
+        assert_round_trip(&map);
+
+        // Runs must add up to the map size
+        let mut writer = BinaryWriter::new();
+        writer.write_u32(2);
+        writer.write_u32(2);
+        writer.write_u8(RUN_LENGTH_LAYERS);
+        writer.write_padded_bytes(&run_length_encode(&[1, 1, 1]));
+        writer.write_padded_bytes(&run_length_encode(&[0, 0, 0, 0]));
+        assert_eq!(
+            Map::from_binary(&writer.into_binary()).err(),
+            Some(DecodeError::LengthMismatch { expected: 4, actual: 3 }),
+        );
+    }
+
+    #[test]
+    fn loads_version_3_raw_map() {
+        let binary_data = vec![
+            2, 0, 0, 0, // Width
+            1, 0, 0, 0, // Height
+            1, 2, // Ground layer
+            0, 2, // Second layer
+        ];
+        let mut reader = BinaryReader::for_format_version(&binary_data, 3).unwrap();
+        let map = Map::read_binary(&mut reader).unwrap();
+        reader.expect_empty().unwrap();
+        assert!(map.get_at(1, 0) == GroundType::Water);
+        assert!(map.get_at_second_level(1, 0) == SecondLevelType::Tree);
+    }
+}
@@ -1,18 +1,22 @@
-use rand::{Rng};
+use rand::{Rng, RngCore};
 use std::collections::HashMap;
 
+use super::game_rng::GameRng;
+
 
 // Inspired by https://en.wikipedia.org/wiki/Perlin_noise
 
 pub struct PerlinNoise {
     zoom_level: u32,
+    seed: u64,
     gradients: HashMap<(i32, i32), (f32, f32)>
 }
 
 impl PerlinNoise {
-    pub fn new(zoom_level: u32) -> PerlinNoise {
+    pub fn new(zoom_level: u32, seed: u64) -> PerlinNoise {
         PerlinNoise {
             zoom_level: zoom_level,
+            seed: seed,
             gradients: HashMap::new()
         }
     }
@@ -27,7 +31,10 @@ impl PerlinNoise {
                 return *gradient;
             }
             _ => {
-                let mut randomizer = rand::thread_rng();
+                // Seeded by the grid point so the gradients do not depend on
+                // the order the noise is sampled in
+                let point_seed = ((x as u32 as u64) << 32) | (y as u32 as u64);
+                let mut randomizer = GameRng::new(self.seed ^ point_seed);
                 let x_value: f32 = randomizer.gen_range(-1.0, 1.0);
                 let y_value: f32 = randomizer.gen_range(-1.0, 1.0);
                 self.gradients.insert((x,y), (x_value, y_value));
@@ -80,12 +87,12 @@ pub struct ComplexNoise {
 
 
 impl ComplexNoise {
-    pub fn new(zoom_level: u32) -> ComplexNoise {
+    pub fn new(zoom_level: u32, rng: &mut GameRng) -> ComplexNoise {
         ComplexNoise {
-            perlin_1: PerlinNoise::new(zoom_level),
-            perlin_2: PerlinNoise::new(zoom_level * 2),
-            perlin_3: PerlinNoise::new(zoom_level * 3),
-            perlin_4: PerlinNoise::new(zoom_level * 5)
+            perlin_1: PerlinNoise::new(zoom_level, rng.next_u64()),
+            perlin_2: PerlinNoise::new(zoom_level * 2, rng.next_u64()),
+            perlin_3: PerlinNoise::new(zoom_level * 3, rng.next_u64()),
+            perlin_4: PerlinNoise::new(zoom_level * 5, rng.next_u64())
         }
     }
 
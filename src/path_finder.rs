@@ -136,3 +136,61 @@ pub fn build_search_tree(map: &map::Map, goal_point: (i32, i32), start_points: &
 }
 
 
+
+
+#[cfg(test)]
+pub mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use super::super::entity_holder::tests::battle_entity_holder;
+
+    #[test]
+    fn finds_path_around_water() {
+        let mut map = map::Map::new(10, 5);
+        for y in 0..4 {
+            map.set(4, y, map::GroundType::Water);
+        }
+        let search_tree = build_search_tree(&map, (8, 1), &vec![(1, 1)]);
+
+        // Following the tree from the start leads to the goal through the gap
+        let mut point = (1, 1);
+        let mut steps = 0;
+        while let Some(Some(next_point)) = search_tree.get(&point) {
+            assert!(map.point_moveable(*next_point));
+            point = *next_point;
+            steps += 1;
+        }
+        assert_eq!(point, (8, 1));
+        assert!(steps >= 7);
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_build_search_tree() {
+        // Run with: cargo test --release -- --ignored --nocapture bench_
+        for amount in [1_000, 5_000, 20_000].iter() {
+            // Orders the first army across the map, as selecting it and right clicking would
+            let (entity_holder, map) = battle_entity_holder(*amount);
+            let mut start_points: Vec<(i32, i32)> = entity_holder.entities_iter()
+                .filter(|entity| entity.team_id() == 0)
+                .map(|entity| entity.location().as_i())
+                .collect();
+            start_points.sort();
+            start_points.dedup();
+            let goal_point = (map.width() as i32 - 2, map.height() as i32 / 2);
+
+            let start_time = Instant::now();
+            let search_tree = build_search_tree(&map, goal_point, &start_points);
+            let elapsed = start_time.elapsed();
+
+            println!(
+                "{:>6} units, {:>6} start points: {:>9.3} ms, {} tiles searched",
+                amount,
+                start_points.len(),
+                elapsed.as_secs_f64() * 1000.0,
+                search_tree.len(),
+            );
+        }
+    }
+}
@@ -1,3 +1,6 @@
+use serde::{Serialize, Deserialize};
+
+
 
 
 #[derive(Debug)]
@@ -66,7 +69,7 @@ impl Vector {
 }
 
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct Point {
     pub x: f32,
     pub y: f32,
@@ -96,6 +99,14 @@ impl Point {
         )
     }
 
+    pub fn interpolated(&self, other: &Point, amount: f32) -> Point {
+        // Point between self (amount 0.0) and other (amount 1.0)
+        Point::new(
+            self.x + (other.x - self.x) * amount,
+            self.y + (other.y - self.y) * amount
+        )
+    }
+
     pub fn as_i(&self) -> (i32, i32) {
         (self.x as i32, self.y as i32)
     }
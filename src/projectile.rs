@@ -1,66 +1,194 @@
 use super::point::Point;
 use super::map;
+use super::entity::{Entity, EntityType};
+use super::unit_types;
 
 
-use super::binary_helpers::Binaryable;
-use super::binary_helpers;
+use super::binary_helpers::{Binaryable, BinaryReader, BinaryWriter, DecodeError};
 
+use serde::{Serialize, Deserialize};
 
+
+// Projectiles in saves before this version did not store who shot them
+const PROJECTILE_OWNER_SINCE_VERSION: u32 = 9;
+
+// Projectiles in saves before this version were all arrows
+const PROJECTILE_TYPE_SINCE_VERSION: u32 = 10;
+
+// Projectiles leave the shooter at about shoulder height, and arcs are this
+// much lower than get_height. Drawing uses the same height.
+const LAUNCH_HEIGHT: f32 = 0.5;
+const ARC_SCALE: f32 = 0.2;
+
+// Tiles a projectile flies per tick
+const PROJECTILE_SPEED: f32 = 0.2;
+
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub enum ProjectileType {
+    // Flies in an arc, so only obstacles close to either end block it
+    #[default]
+    Arrow,
+    // Flies straight, so every tall obstacle on the way blocks it
+    Bolt,
+}
+
+
+impl Binaryable for ProjectileType {
+    fn write_binary(&self, writer: &mut BinaryWriter) {
+        writer.write_u8(*self as u8);
+    }
+
+    fn read_binary(reader: &mut BinaryReader) -> Result<ProjectileType, DecodeError> {
+        let projectile_type = reader.read_u8()?;
+        match projectile_type {
+            0 => Ok(ProjectileType::Arrow),
+            1 => Ok(ProjectileType::Bolt),
+            _ => Err(DecodeError::UnknownProjectileType(projectile_type)),
+        }
+    }
+}
+
+
+#[derive(Serialize, Deserialize)]
 pub struct Projectile {
     location: Point,
     start_point: Point,
     end_point: Point,
     angle: f32,
+
+    // The shooter gets the kill credit, if it is known and still alive
+    #[serde(default)]
+    shooter_id: Option<u32>,
+    team_id: u32,
+    #[serde(default)]
+    target_id: Option<u32>,
+    damage: u32,
+    #[serde(default)]
+    splash_radius: f32,
+    #[serde(default)]
+    friendly_fire: bool,
+    #[serde(default)]
+    projectile_type: ProjectileType,
 }
 
 
 impl Binaryable for Projectile {
-    fn as_binary(&self) -> Vec<u8> {
-        let mut binary_data: Vec<u8> = Vec::new();
-
-        binary_data.extend(binary_helpers::f32_as_bytes(self.location.x));
-        binary_data.extend(binary_helpers::f32_as_bytes(self.location.y));
-        binary_data.extend(binary_helpers::f32_as_bytes(self.start_point.x));
-        binary_data.extend(binary_helpers::f32_as_bytes(self.start_point.y));
-        binary_data.extend(binary_helpers::f32_as_bytes(self.end_point.x));
-        binary_data.extend(binary_helpers::f32_as_bytes(self.end_point.y));
-        binary_data.extend(binary_helpers::f32_as_bytes(self.angle));
-
-        binary_data
-    }
-
-    fn from_binary(binary_data: Vec<u8>) -> Projectile {
-        let (location_x, binary_data) = binary_helpers::pop_f32(binary_data);
-        let (location_y, binary_data) = binary_helpers::pop_f32(binary_data);
-        let (start_point_x, binary_data) = binary_helpers::pop_f32(binary_data);
-        let (start_point_y, binary_data) = binary_helpers::pop_f32(binary_data);
-        let (end_point_x, binary_data) = binary_helpers::pop_f32(binary_data);
-        let (end_point_y, binary_data) = binary_helpers::pop_f32(binary_data);
-        let (angle, binary_data) = binary_helpers::pop_f32(binary_data);
-        Projectile {
+    fn write_binary(&self, writer: &mut BinaryWriter) {
+        writer.write_f32(self.location.x);
+        writer.write_f32(self.location.y);
+        writer.write_f32(self.start_point.x);
+        writer.write_f32(self.start_point.y);
+        writer.write_f32(self.end_point.x);
+        writer.write_f32(self.end_point.y);
+        writer.write_f32(self.angle);
+        write_optional_id(writer, self.shooter_id);
+        writer.write_u32(self.team_id);
+        write_optional_id(writer, self.target_id);
+        writer.write_u32(self.damage);
+        writer.write_f32(self.splash_radius);
+        writer.write_u8(self.friendly_fire as u8);
+        self.projectile_type.write_binary(writer);
+    }
+
+    fn read_binary(reader: &mut BinaryReader) -> Result<Projectile, DecodeError> {
+        let location_x = reader.read_f32()?;
+        let location_y = reader.read_f32()?;
+        let start_point_x = reader.read_f32()?;
+        let start_point_y = reader.read_f32()?;
+        let end_point_x = reader.read_f32()?;
+        let end_point_y = reader.read_f32()?;
+        let angle = reader.read_f32()?;
+        let mut projectile = Projectile {
             location: Point::new(location_x, location_y),
             start_point: Point::new(start_point_x, start_point_y),
             end_point: Point::new(end_point_x, end_point_y),
-            angle: angle
+            angle: angle,
+            // Older projectiles hit whoever they landed on with the damage
+            // of ranged units
+            shooter_id: None,
+            team_id: 0,
+            target_id: None,
+            damage: unit_types::stats(&EntityType::Ranged).damage,
+            splash_radius: 0.0,
+            friendly_fire: true,
+            projectile_type: ProjectileType::Arrow,
+        };
+        if reader.format_version() >= PROJECTILE_OWNER_SINCE_VERSION {
+            projectile.shooter_id = read_optional_id(reader)?;
+            projectile.team_id = reader.read_u32()?;
+            projectile.target_id = read_optional_id(reader)?;
+            projectile.damage = reader.read_u32()?;
+            projectile.splash_radius = reader.read_f32()?;
+            projectile.friendly_fire = reader.read_u8()? != 0;
+        }
+        if reader.format_version() >= PROJECTILE_TYPE_SINCE_VERSION {
+            projectile.projectile_type = ProjectileType::read_binary(reader)?;
+        }
+        Ok(projectile)
+    }
+}
+
+
+fn write_optional_id(writer: &mut BinaryWriter, id: Option<u32>) {
+    match id {
+        Some(id) => {
+            writer.write_u8(1u8);
+            writer.write_u32(id);
+        },
+        None => {
+            writer.write_u8(0u8);
+            writer.write_u32(0u32);
         }
     }
 }
 
+fn read_optional_id(reader: &mut BinaryReader) -> Result<Option<u32>, DecodeError> {
+    let exists = reader.read_u8()?;
+    let id = reader.read_u32()?;
+    Ok(if exists != 0 { Some(id) } else { None })
+}
+
 
 impl Projectile {
-    pub fn new(start_point: &Point, end_point: &Point) -> Projectile {
+    pub fn new(shooter: &Entity, end_point: &Point) -> Projectile {
+        // Shot from the shooter's location at its closest seen enemy
+        let start_point = shooter.location();
         let vector = end_point.dist_to(start_point);
         let angle = vector.angle();
+        let stats = shooter.stats();
         Projectile {
             location: Point::new(start_point.x, start_point.y),
             start_point: Point::new(start_point.x, start_point.y),
             end_point: Point::new(end_point.x, end_point.y),
             angle: angle,
+            shooter_id: Some(shooter.id()),
+            team_id: shooter.team_id(),
+            target_id: *shooter.closest_seen_enemy_id(),
+            damage: stats.damage,
+            splash_radius: stats.splash_radius,
+            friendly_fire: stats.friendly_fire,
+            projectile_type: stats.projectile_type,
         }
     }
 
     pub fn location(&self) -> &Point { &self.location }
+    pub fn end_point(&self) -> &Point { &self.end_point }
     pub fn angle(&self) -> f32 { self.angle}
+    pub fn shooter_id(&self) -> Option<u32> { self.shooter_id }
+    pub fn team_id(&self) -> u32 { self.team_id }
+    pub fn target_id(&self) -> Option<u32> { self.target_id }
+    pub fn damage(&self) -> u32 { self.damage }
+    pub fn splash_radius(&self) -> f32 { self.splash_radius }
+    pub fn friendly_fire(&self) -> bool { self.friendly_fire }
+    pub fn projectile_type(&self) -> ProjectileType { self.projectile_type }
+
+    pub fn can_hit(&self, entity: &Entity) -> bool {
+        // Never hits its own shooter, and others of its team only with friendly fire
+        entity.alive() &&
+            Some(entity.id()) != self.shooter_id &&
+            (self.friendly_fire || entity.team_id() != self.team_id)
+    }
 
     pub fn increment(&mut self) {
         let dist_vect = self.location.dist_to(&self.end_point);
@@ -68,10 +196,17 @@ impl Projectile {
             self.location.x = self.end_point.x;
             self.location.y = self.end_point.y;
         } else {
-            self.location = self.location.added(&self.location.dist_to(&self.end_point).normalized().multiplied(-0.2));
+            self.location = self.location.added(&self.location.dist_to(&self.end_point).normalized().multiplied(-PROJECTILE_SPEED));
         }
     }
 
+    pub fn previous_location(&self) -> Point {
+        // Location before the last increment, for drawing between ticks
+        let back = self.start_point.dist_to(&self.location);
+        let step = back.length().min(PROJECTILE_SPEED);
+        self.location.added(&back.normalized().multiplied(step))
+    }
+
     pub fn at_location(&self) -> bool {
         return self.location.x == self.end_point.x && self.location.y == self.end_point.y;
     }
@@ -87,4 +222,121 @@ impl Projectile {
         
         y * total_length
     }
+
+    pub fn height(&self) -> f32 {
+        // Height above the ground in tiles
+        match self.projectile_type {
+            ProjectileType::Arrow => LAUNCH_HEIGHT + ARC_SCALE * self.get_height(),
+            ProjectileType::Bolt => LAUNCH_HEIGHT,
+        }
+    }
+
+    pub fn blocked_by(&self, map: &map::Map) -> bool {
+        // True if the projectile flies lower than the obstacle on its tile
+        let obstacle = map.get_at_second_level(self.location.x.floor() as i32, self.location.y.floor() as i32);
+        self.height() < obstacle.obstacle_height()
+    }
+}
+
+
+#[cfg(test)]
+pub mod tests {
+    use rand::Rng;
+    use rand::rngs::SmallRng;
+
+    use super::*;
+    use super::super::binary_helpers::tests::{
+        assert_round_trip, property_test_rng, random_f32, PROPERTY_TEST_CASES
+    };
+
+    pub fn random_projectile(rng: &mut SmallRng) -> Projectile {
+        Projectile {
+            location: Point::new(random_f32(rng), random_f32(rng)),
+            start_point: Point::new(random_f32(rng), random_f32(rng)),
+            end_point: Point::new(random_f32(rng), random_f32(rng)),
+            angle: random_f32(rng),
+            shooter_id: if rng.gen() {Some(rng.gen())} else {None},
+            team_id: rng.gen(),
+            target_id: if rng.gen() {Some(rng.gen())} else {None},
+            damage: rng.gen(),
+            splash_radius: random_f32(rng),
+            friendly_fire: rng.gen(),
+            projectile_type: if rng.gen() {ProjectileType::Arrow} else {ProjectileType::Bolt},
+        }
+    }
+
+    pub fn set_splash(projectile: &mut Projectile, splash_radius: f32, friendly_fire: bool) {
+        projectile.splash_radius = splash_radius;
+        projectile.friendly_fire = friendly_fire;
+    }
+
+    pub fn write_legacy_projectile(writer: &mut BinaryWriter, projectile: &Projectile, format_version: u32) {
+        // Projectile as older versions wrote it, for tests loading older saves
+        let mut binary_data = projectile.as_binary();
+        if format_version < PROJECTILE_OWNER_SINCE_VERSION {
+            // Only the location, start point, end point and angle
+            binary_data.truncate(7 * 4);
+        } else if format_version < PROJECTILE_TYPE_SINCE_VERSION {
+            // The projectile type is the last byte
+            binary_data.pop();
+        }
+        writer.write_bytes(&binary_data);
+    }
+
+    #[test]
+    fn previous_location_is_the_location_before_increment() {
+        let shooter = Entity::new(1.0, 1.0, 0, 0, EntityType::Ranged);
+        let mut projectile = Projectile::new(&shooter, &Point::new(4.0, 5.0));
+        assert_eq!(projectile.previous_location().as_f(), (1.0, 1.0));
+        for _ in 0..3 {
+            let location = *projectile.location();
+            projectile.increment();
+            let previous_location = projectile.previous_location();
+            assert!((previous_location.x - location.x).abs() < 0.0001);
+            assert!((previous_location.y - location.y).abs() < 0.0001);
+        }
+    }
+
+    #[test]
+    fn loads_version_8_projectile() {
+        // Version 8 projectiles stored only where they fly
+        let shooter = Entity::new(1.0, 1.0, 0, 0, EntityType::Ranged);
+        let mut writer = BinaryWriter::new();
+        write_legacy_projectile(&mut writer, &Projectile::new(&shooter, &Point::new(4.0, 5.0)), 8);
+        let binary_data = writer.into_binary();
+        assert_eq!(binary_data.len(), 7 * 4);
+        let mut reader = BinaryReader::for_format_version(&binary_data, 8).unwrap();
+        let projectile = Projectile::read_binary(&mut reader).unwrap();
+        assert!(reader.is_empty());
+        assert_eq!(projectile.end_point().as_f(), (4.0, 5.0));
+        assert_eq!(projectile.shooter_id(), None);
+        assert_eq!(projectile.damage(), unit_types::stats(&EntityType::Ranged).damage);
+        assert_eq!(projectile.projectile_type(), ProjectileType::Arrow);
+
+        let teammate = Entity::new(4.0, 5.0, 3, 0, EntityType::Meelee);
+        assert!(projectile.can_hit(&teammate));
+    }
+
+    #[test]
+    fn arrows_fly_over_trees_that_block_bolts() {
+        let mut map = map::Map::new(10, 3);
+        map.set_second_layer(4, 1, map::SecondLevelType::Tree);
+        let shooter = Entity::new(0.5, 1.5, 0, 0, EntityType::Ranged);
+        let mut arrow = Projectile::new(&shooter, &Point::new(9.5, 1.5));
+        let mut bolt = Projectile::new(&shooter, &Point::new(9.5, 1.5));
+        bolt.projectile_type = ProjectileType::Bolt;
+        while arrow.location().x < 4.5 {
+            arrow.increment();
+            bolt.increment();
+            assert!(!arrow.blocked_by(&map));
+        }
+        assert!(bolt.blocked_by(&map));
+    }
+
+    #[test]
+    fn projectile_round_trip() {
+        for case in 0..PROPERTY_TEST_CASES {
+            assert_round_trip(&random_projectile(&mut property_test_rng(case)));
+        }
+    }
 }
@@ -0,0 +1,399 @@
+use std::path::Path;
+
+use super::game_state::{GameState, GameEvent, ScheduledEvent};
+use super::entity::EntityType;
+use super::unit_types;
+use super::save_slots::write_atomically;
+use super::binary_helpers::{Binaryable, BinaryReader, BinaryWriter, DecodeError, SAVE_FORMAT_VERSION};
+
+
+// Replay files start with these magic bytes followed by the format version.
+// The rest of the file uses the same records as save files.
+pub const REPLAY_FILE_MAGIC: &[u8; 4] = b"ORPL";
+
+// Replays were added in this format version
+const REPLAYS_SINCE_VERSION: u32 = 5;
+
+// Replays before this version applied the events of a tick after the entity
+// AI instead of before it, which is the same as the start of the next tick
+const EVENTS_BEFORE_AI_SINCE_VERSION: u32 = 6;
+
+// Replays since this version store a hash of the unit definitions they were
+// recorded with
+const UNIT_DEFINITIONS_HASH_SINCE_VERSION: u32 = 7;
+
+// Before this version InsertUnit ignored its unit type and picked the type
+// from the id the new unit got
+const INSERTED_UNIT_TYPES_SINCE_VERSION: u32 = 8;
+
+// Ticks jumped per seek step in playback
+pub const SEEK_STEP: u32 = 600;
+
+// Fast forward speeds, in ticks per frame
+const PLAYBACK_SPEEDS: [u32; 5] = [1, 2, 4, 8, 16];
+
+
+// Starting state of a game and every event applied to it. Simulating the
+// starting state while applying the events on their ticks reproduces the game.
+pub struct Replay {
+    // GameState as written by as_binary, decoded again for every playback
+    initial_state: Vec<u8>,
+    start_tick: u32,
+    end_tick: u32,
+    events: Vec<ScheduledEvent>,
+    // unit_types::simulation_hash when recorded, unknown for older replays
+    unit_definitions_hash: Option<u64>,
+}
+
+
+impl Replay {
+    pub fn record(game_state: &mut GameState) -> Replay {
+        // Starts recording the events applied to game_state from now on
+        game_state.start_recording();
+        Replay {
+            initial_state: game_state.as_binary(),
+            start_tick: game_state.tick(),
+            end_tick: game_state.tick(),
+            events: Vec::new(),
+            unit_definitions_hash: Some(unit_types::simulation_hash()),
+        }
+    }
+
+    pub fn update(&mut self, game_state: &mut GameState) {
+        // Collects the events recorded since the last update
+        self.events.extend(game_state.take_recorded_events());
+        self.end_tick = game_state.tick();
+    }
+
+    pub fn initial_state(&self) -> GameState {
+        GameState::from_binary(&self.initial_state).expect("Replay initial state is validated when loaded")
+    }
+
+    pub fn start_tick(&self) -> u32 { self.start_tick }
+    pub fn end_tick(&self) -> u32 { self.end_tick }
+    pub fn events(&self) -> &Vec<ScheduledEvent> { &self.events }
+
+    pub fn matches_unit_definitions(&self) -> bool {
+        // A replay only plays out the same with the unit definitions it was
+        // recorded with. Replays that do not know them are assumed to match.
+        match self.unit_definitions_hash {
+            Some(hash) => hash == unit_types::simulation_hash(),
+            None => true,
+        }
+    }
+
+    pub fn as_file_binary(&self) -> Vec<u8> {
+        let mut writer = BinaryWriter::new();
+        writer.write_bytes(REPLAY_FILE_MAGIC);
+        writer.write_u32(SAVE_FORMAT_VERSION);
+        writer.write_u32(self.end_tick);
+        // Older replays were assumed to match the current definitions
+        writer.write_u64(self.unit_definitions_hash.unwrap_or_else(unit_types::simulation_hash));
+        writer.write_padded_bytes(&self.initial_state);
+        writer.write_list(self.events.iter());
+        writer.write_checksum();
+        writer.into_binary()
+    }
+
+    pub fn from_file_binary(binary_data: &[u8]) -> Result<Replay, DecodeError> {
+        let mut reader = BinaryReader::new(binary_data);
+        if reader.peek_bytes().len() < REPLAY_FILE_MAGIC.len()
+            || reader.read_bytes(REPLAY_FILE_MAGIC.len())? != REPLAY_FILE_MAGIC {
+            return Err(DecodeError::NotAReplayFile);
+        }
+        let format_version = reader.read_u32()?;
+        if format_version < REPLAYS_SINCE_VERSION {
+            return Err(DecodeError::UnsupportedVersion(format_version));
+        }
+        reader.set_format_version(format_version)?;
+        reader.verify_trailing_checksum()?;
+
+        let end_tick = reader.read_u32()?;
+        let unit_definitions_hash = if format_version >= UNIT_DEFINITIONS_HASH_SINCE_VERSION {
+            Some(reader.read_u64()?)
+        } else {
+            None
+        };
+        let initial_state = reader.read_record::<GameState>()?;
+        let mut events = reader.read_list::<ScheduledEvent>()?;
+        reader.expect_empty()?;
+        if format_version < EVENTS_BEFORE_AI_SINCE_VERSION {
+            for scheduled_event in events.iter_mut() {
+                scheduled_event.tick += 1;
+            }
+        }
+        if format_version < INSERTED_UNIT_TYPES_SINCE_VERSION {
+            // Only InsertUnit creates entities, so the ids follow from the event order
+            let mut next_id = initial_state.entity_holder().id_counter;
+            for scheduled_event in events.iter_mut() {
+                if let GameEvent::InsertUnit { unit_type, .. } = &mut scheduled_event.game_event {
+                    *unit_type = legacy_unit_type(next_id);
+                    next_id += 1;
+                }
+            }
+        }
+
+        Ok(Replay {
+            // Stored in the current format so it can be decoded without a version
+            initial_state: initial_state.as_binary(),
+            start_tick: initial_state.tick(),
+            end_tick: end_tick,
+            events: events,
+            unit_definitions_hash: unit_definitions_hash,
+        })
+    }
+
+    pub fn load_file(file_name: &str) -> Result<Replay, DecodeError> {
+        let binary_data = std::fs::read(file_name)
+            .map_err(|error| DecodeError::Io(format!("{}: {}", file_name, error)))?;
+        Replay::from_file_binary(&binary_data)
+    }
+
+    pub fn save_to_file(&self, file_name: &str) -> std::io::Result<()> {
+        write_atomically(Path::new(file_name), &self.as_file_binary())
+    }
+}
+
+
+fn legacy_unit_type(entity_id: u32) -> EntityType {
+    match entity_id % 3 {
+        0 => EntityType::Ranged,
+        1 => EntityType::Peasant,
+        _ => EntityType::Meelee,
+    }
+}
+
+
+// Plays a replay back on a GameState, with pause, fast forward and seeking
+pub struct ReplayPlayer {
+    replay: Replay,
+    next_event_index: usize,
+    speed_index: usize,
+    pub paused: bool,
+}
+
+
+impl ReplayPlayer {
+    pub fn new(replay: Replay) -> ReplayPlayer {
+        ReplayPlayer {
+            replay: replay,
+            next_event_index: 0,
+            speed_index: 0,
+            paused: false,
+        }
+    }
+
+    pub fn replay(&self) -> &Replay { &self.replay }
+
+    pub fn speed(&self) -> u32 { PLAYBACK_SPEEDS[self.speed_index] }
+
+    pub fn toggle_fast_forward(&mut self) {
+        // Cycles through the speeds, back to normal speed after the fastest
+        self.speed_index = (self.speed_index + 1) % PLAYBACK_SPEEDS.len();
+    }
+
+    pub fn is_finished(&self, game_state: &GameState) -> bool {
+        game_state.tick() >= self.replay.end_tick
+    }
+
+    pub fn restart(&mut self, game_state: &mut GameState) {
+        let debug_state_hash_interval = game_state.debug_state_hash_interval;
+        *game_state = self.replay.initial_state();
+        game_state.debug_state_hash_interval = debug_state_hash_interval;
+        self.next_event_index = 0;
+    }
+
+    pub fn step(&mut self, game_state: &mut GameState) {
+        // Simulates one tick, applying the events recorded for it. Orders
+        // given while watching are dropped so the game plays out as recorded.
+        game_state.discard_pending_events();
+        let tick = game_state.tick() + 1;
+        while self.next_event_index < self.replay.events.len()
+            && self.replay.events[self.next_event_index].tick <= tick {
+            let scheduled_event = &self.replay.events[self.next_event_index];
+            game_state.schedule_event(scheduled_event.tick, scheduled_event.game_event.clone());
+            self.next_event_index += 1;
+        }
+        game_state.do_tick();
+    }
+
+    pub fn update(&mut self, game_state: &mut GameState) {
+        // Advances playback by one frame
+        if self.paused {
+            return;
+        }
+        for _ in 0..self.speed() {
+            if self.is_finished(game_state) {
+                self.paused = true;
+                return;
+            }
+            self.step(game_state);
+        }
+    }
+
+    pub fn seek(&mut self, game_state: &mut GameState, tick: u32) {
+        // Seeking backwards simulates again from the start of the replay
+        let tick = tick.max(self.replay.start_tick).min(self.replay.end_tick);
+        if tick < game_state.tick() {
+            self.restart(game_state);
+        }
+        while game_state.tick() < tick {
+            self.step(game_state);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use super::super::point::Point;
+    use super::super::game_state::GameEvent;
+    use super::super::map::GroundType;
+    use super::super::entity::{EntityType, Task};
+    use super::super::game_state::tests::{battle_game_state, simulation_snapshot, write_legacy_game_state};
+
+    fn recorded_battle() -> (Replay, GameState) {
+        // Battle with orders given along the way, several of them on the same tick
+        let mut game_state = battle_game_state();
+        let mut replay = Replay::record(&mut game_state);
+        for tick in 0..400 {
+            if tick == 20 {
+                game_state.dispatch_event(GameEvent::InsertUnit {
+                    location: Point::new(2.5, 30.5), team_id: 0, unit_type: EntityType::Ranged,
+                });
+                game_state.dispatch_event(GameEvent::SetMapPoint { location: (5, 5), ground_type: GroundType::Water });
+            }
+            if tick == 60 {
+                let mut unit_ids: HashMap<u32, bool> = HashMap::new();
+                unit_ids.insert(8, true);
+                game_state.dispatch_event(GameEvent::OrderUnits {
+                    task: Task::Move { point: Point::new(2.5, 25.5) },
+                    unit_ids: unit_ids,
+                });
+                game_state.dispatch_event(GameEvent::AddBuilding { location: (28, 2) });
+            }
+            game_state.do_tick();
+        }
+        replay.update(&mut game_state);
+        (replay, game_state)
+    }
+
+    #[test]
+    fn replay_file_round_trip() {
+        let (replay, _game_state) = recorded_battle();
+        assert_eq!(replay.events().len(), 4);
+        assert_eq!(replay.end_tick(), 400);
+
+        let binary_data = replay.as_file_binary();
+        let decoded = Replay::from_file_binary(&binary_data).unwrap();
+        assert_eq!(decoded.start_tick(), 0);
+        assert_eq!(decoded.as_file_binary(), binary_data);
+
+        assert_eq!(Replay::from_file_binary(b"ORTS").err(), Some(DecodeError::NotAReplayFile));
+        let mut corrupted = binary_data.clone();
+        corrupted[20] ^= 1;
+        assert!(Replay::from_file_binary(&corrupted).is_err());
+    }
+
+    #[test]
+    fn loads_version_5_replay() {
+        // Version 5 applied events after the entity AI of their tick
+        let mut writer = BinaryWriter::new();
+        writer.write_bytes(REPLAY_FILE_MAGIC);
+        writer.write_u32(5);
+        writer.write_u32(30);
+        writer.write_padded(|writer| write_legacy_game_state(writer, &battle_game_state(), 5));
+        writer.write_list(vec![ScheduledEvent {
+            tick: 10,
+            game_event: GameEvent::SetMapPoint { location: (5, 5), ground_type: GroundType::Water },
+        }].iter());
+        writer.write_checksum();
+
+        let replay = Replay::from_file_binary(&writer.into_binary()).unwrap();
+        assert_eq!(replay.events()[0].tick, 11);
+        assert!(replay.matches_unit_definitions());
+
+        let mut player = ReplayPlayer::new(replay);
+        let mut game_state = player.replay().initial_state();
+        player.seek(&mut game_state, 10);
+        assert_eq!(game_state.map().get_at(5, 5), GroundType::Grass);
+        player.seek(&mut game_state, 11);
+        assert_eq!(game_state.map().get_at(5, 5), GroundType::Water);
+    }
+
+    #[test]
+    fn replays_remember_their_unit_definitions() {
+        let (replay, _game_state) = recorded_battle();
+        let decoded = Replay::from_file_binary(&replay.as_file_binary()).unwrap();
+        assert!(decoded.matches_unit_definitions());
+
+        let mut writer = BinaryWriter::new();
+        writer.write_bytes(REPLAY_FILE_MAGIC);
+        writer.write_u32(SAVE_FORMAT_VERSION);
+        writer.write_u32(0);
+        writer.write_u64(unit_types::simulation_hash() ^ 1);
+        writer.write_record(&battle_game_state());
+        writer.write_list(Vec::<ScheduledEvent>::new().iter());
+        writer.write_checksum();
+        let other_units = Replay::from_file_binary(&writer.into_binary()).unwrap();
+        assert!(!other_units.matches_unit_definitions());
+    }
+
+    #[test]
+    fn loads_version_7_replay_with_legacy_unit_types() {
+        // Version 7 picked the type of inserted units from their id
+        let initial_state = battle_game_state();
+        assert_eq!(initial_state.entity_holder().id_counter, 8);
+        let insert_unit = |y: f32| ScheduledEvent {
+            tick: 2,
+            game_event: GameEvent::InsertUnit {
+                location: Point::new(2.5, y), team_id: 0, unit_type: EntityType::Ranged,
+            },
+        };
+        let mut writer = BinaryWriter::new();
+        writer.write_bytes(REPLAY_FILE_MAGIC);
+        writer.write_u32(7);
+        writer.write_u32(10);
+        writer.write_u64(unit_types::simulation_hash());
+        writer.write_padded(|writer| write_legacy_game_state(writer, &initial_state, 7));
+        writer.write_list(vec![insert_unit(2.5), insert_unit(4.5), insert_unit(6.5)].iter());
+        writer.write_checksum();
+
+        let replay = Replay::from_file_binary(&writer.into_binary()).unwrap();
+        let mut player = ReplayPlayer::new(replay);
+        let mut game_state = player.replay().initial_state();
+        player.seek(&mut game_state, 10);
+        let inserted_types: Vec<EntityType> = (8..11)
+            .map(|id| game_state.entity_holder().entities[&id].entity_type().clone())
+            .collect();
+        assert_eq!(inserted_types, vec![EntityType::Meelee, EntityType::Ranged, EntityType::Peasant]);
+        assert!(player.replay().matches_unit_definitions());
+    }
+
+    #[test]
+    fn playback_reproduces_the_recorded_game() {
+        let (replay, recorded) = recorded_battle();
+        let replay = Replay::from_file_binary(&replay.as_file_binary()).unwrap();
+        let mut player = ReplayPlayer::new(replay);
+        let mut game_state = player.replay().initial_state();
+
+        player.toggle_fast_forward();
+        while !player.paused {
+            player.update(&mut game_state);
+        }
+        assert!(player.is_finished(&game_state));
+        assert_eq!(simulation_snapshot(&game_state), simulation_snapshot(&recorded));
+        assert_eq!(game_state.map().get_at(5, 5), GroundType::Water);
+        assert_eq!(game_state.entity_holder().buildings.len(), 1);
+
+        // Seeking back and forward again ends up in the same state
+        player.seek(&mut game_state, 50);
+        assert_eq!(game_state.tick(), 50);
+        player.seek(&mut game_state, 1000);
+        assert_eq!(game_state.tick(), 400);
+        assert_eq!(simulation_snapshot(&game_state), simulation_snapshot(&recorded));
+    }
+}
@@ -0,0 +1,243 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use super::game_state::GameState;
+use super::binary_helpers::DecodeError;
+
+
+pub const DEFAULT_SAVE_DIRECTORY: &str = "saves";
+pub const QUICKSAVE_SLOT: &str = "quicksave";
+pub const EXPORT_SLOT: &str = "export";
+
+const BINARY_EXTENSION: &str = "dat";
+const TEXT_EXTENSION: &str = "json";
+const AUTOSAVE_PREFIX: &str = "autosave-";
+const TEMPORARY_EXTENSION: &str = "tmp";
+
+
+pub fn write_atomically(path: &Path, data: &[u8]) -> io::Result<()> {
+    // Writes to a temporary file next to the target and renames it over the
+    // target, so a crash while writing never leaves a partially written save
+    let mut temporary_path = path.as_os_str().to_owned();
+    temporary_path.push(".");
+    temporary_path.push(TEMPORARY_EXTENSION);
+    let temporary_path = PathBuf::from(temporary_path);
+
+    let result = File::create(&temporary_path)
+        .and_then(|mut file| {
+            file.write_all(data)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temporary_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary_path);
+    }
+    result
+}
+
+
+// Named saves stored as <directory>/<name>.dat
+pub struct SaveSlots {
+    directory: PathBuf,
+
+    // Autosave every n:th tick, 0 disables autosaving
+    pub autosave_interval: u32,
+    // Autosaves rotate through this many slots, overwriting the least recently written one
+    pub autosave_count: u32,
+}
+
+
+impl SaveSlots {
+    pub fn new(directory: &str) -> SaveSlots {
+        SaveSlots {
+            directory: PathBuf::from(directory),
+            autosave_interval: 3600,
+            autosave_count: 3,
+        }
+    }
+
+    pub fn directory(&self) -> &PathBuf { &self.directory }
+
+    fn slot_path(&self, name: &str, extension: &str) -> io::Result<PathBuf> {
+        // Slot names are plain file names so a slot can not point outside the directory
+        if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid save slot name {:?}", name),
+            ));
+        }
+        Ok(self.directory.join(format!("{}.{}", name, extension)))
+    }
+
+    pub fn save(&self, name: &str, game_state: &GameState) -> io::Result<PathBuf> {
+        let path = self.slot_path(name, BINARY_EXTENSION)?;
+        fs::create_dir_all(&self.directory)?;
+        write_atomically(&path, &game_state.as_save_binary())?;
+        Ok(path)
+    }
+
+    pub fn export_text(&self, name: &str, game_state: &GameState) -> io::Result<PathBuf> {
+        let path = self.slot_path(name, TEXT_EXTENSION)?;
+        fs::create_dir_all(&self.directory)?;
+        write_atomically(&path, game_state.to_json().as_bytes())?;
+        Ok(path)
+    }
+
+    pub fn load(&self, name: &str) -> Result<GameState, DecodeError> {
+        let path = self.slot_path(name, BINARY_EXTENSION)
+            .map_err(|error| DecodeError::Io(error.to_string()))?;
+        GameState::from_file_name(path.to_string_lossy().to_string())
+    }
+
+    pub fn exists(&self, name: &str) -> bool {
+        match self.slot_path(name, BINARY_EXTENSION) {
+            Ok(path) => path.is_file(),
+            Err(_) => false,
+        }
+    }
+
+    pub fn list(&self) -> Vec<String> {
+        // Names of all binary saves in the directory, sorted
+        let mut names: Vec<String> = Vec::new();
+        let entries = match fs::read_dir(&self.directory) {
+            Ok(entries) => entries,
+            Err(_) => return names,
+        };
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+            if path.extension().is_some_and(|extension| extension == BINARY_EXTENSION) {
+                if let Some(name) = path.file_stem() {
+                    names.push(name.to_string_lossy().to_string());
+                }
+            }
+        }
+        names.sort();
+        names
+    }
+
+    pub fn autosave_slot(&self) -> String {
+        // An unused slot if there is one, otherwise the least recently written one.
+        // Going by the files rather than the tick keeps a quickload to an earlier
+        // tick from overwriting the newest autosave
+        let mut oldest: Option<(SystemTime, String)> = None;
+        for index in 0..self.autosave_count.max(1) {
+            let name = format!("{}{}", AUTOSAVE_PREFIX, index);
+            let modified = self.slot_path(&name, BINARY_EXTENSION)
+                .and_then(fs::metadata)
+                .and_then(|metadata| metadata.modified());
+            match modified {
+                Ok(modified) => {
+                    if oldest.as_ref().is_none_or(|(oldest_modified, _)| modified < *oldest_modified) {
+                        oldest = Some((modified, name));
+                    }
+                },
+                Err(_) => return name,
+            }
+        }
+        oldest.map(|(_, name)| name).unwrap_or_else(|| format!("{}0", AUTOSAVE_PREFIX))
+    }
+
+    pub fn autosave(&self, game_state: &GameState) -> io::Result<Option<String>> {
+        // Saves to the next autosave slot if an autosave is due on this tick
+        if self.autosave_interval == 0 || !game_state.tick().is_multiple_of(self.autosave_interval) {
+            return Ok(None);
+        }
+        let name = self.autosave_slot();
+        self.save(&name, game_state)?;
+        Ok(Some(name))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+    use std::time::Duration;
+
+    use super::*;
+
+    fn test_save_slots(test_name: &str) -> SaveSlots {
+        let directory = env::temp_dir().join(format!("oskurts-{}-{}", test_name, process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        SaveSlots::new(&directory.to_string_lossy())
+    }
+
+    fn game_state_at_tick(tick: u32) -> GameState {
+        GameState::from_json(&format!(r#"{{"tick": {}, "map": {{"ground_layer": ["..."]}}}}"#, tick)).unwrap()
+    }
+
+    #[test]
+    fn save_and_load_slots() {
+        let save_slots = test_save_slots("slots");
+        assert!(save_slots.list().is_empty());
+
+        save_slots.save("second", &game_state_at_tick(2)).unwrap();
+        save_slots.save(QUICKSAVE_SLOT, &game_state_at_tick(5)).unwrap();
+        save_slots.export_text(EXPORT_SLOT, &game_state_at_tick(7)).unwrap();
+
+        assert_eq!(save_slots.list(), vec![QUICKSAVE_SLOT.to_string(), "second".to_string()]);
+        assert!(save_slots.exists("second"));
+        assert!(!save_slots.exists("missing"));
+        assert_eq!(save_slots.load(QUICKSAVE_SLOT).unwrap().tick(), 5);
+        assert!(save_slots.load("missing").is_err());
+
+        // Overwriting leaves no temporary files behind
+        save_slots.save("second", &game_state_at_tick(3)).unwrap();
+        assert_eq!(save_slots.load("second").unwrap().tick(), 3);
+        assert_eq!(fs::read_dir(save_slots.directory()).unwrap().count(), 3);
+
+        fs::remove_dir_all(save_slots.directory()).unwrap();
+    }
+
+    #[test]
+    fn slot_names_can_not_leave_the_directory() {
+        let save_slots = test_save_slots("names");
+        for name in ["", "../escape", "a/b", ".hidden"].iter() {
+            assert!(save_slots.save(name, &game_state_at_tick(0)).is_err());
+        }
+        assert!(save_slots.list().is_empty());
+    }
+
+    #[test]
+    fn autosaves_rotate() {
+        let mut save_slots = test_save_slots("autosave");
+        save_slots.autosave_interval = 10;
+        save_slots.autosave_count = 2;
+
+        // Stamp each autosave with a distinct time so the test does not depend on
+        // the file system's timestamp resolution
+        let mut seconds = 0;
+        let mut autosave = |save_slots: &SaveSlots, tick: u32| {
+            let name = save_slots.autosave(&game_state_at_tick(tick)).unwrap()?;
+            seconds += 1;
+            let path = save_slots.slot_path(&name, BINARY_EXTENSION).unwrap();
+            File::options().write(true).open(path).unwrap()
+                .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)).unwrap();
+            Some(name)
+        };
+
+        assert_eq!(autosave(&save_slots, 5), None);
+        for tick in [10, 20, 30].iter() {
+            assert!(autosave(&save_slots, *tick).is_some());
+        }
+
+        assert_eq!(save_slots.list(), vec!["autosave-0".to_string(), "autosave-1".to_string()]);
+        assert_eq!(save_slots.load("autosave-0").unwrap().tick(), 30);
+        assert_eq!(save_slots.load("autosave-1").unwrap().tick(), 20);
+
+        // After loading back to an earlier tick the next autosave still replaces
+        // the oldest slot instead of the newest one
+        assert_eq!(autosave(&save_slots, 20), Some("autosave-1".to_string()));
+        assert_eq!(save_slots.load("autosave-0").unwrap().tick(), 30);
+
+        save_slots.autosave_interval = 0;
+        assert_eq!(autosave(&save_slots, 40), None);
+
+        fs::remove_dir_all(save_slots.directory()).unwrap();
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use super::entity::{EntityType, ResourceType};
+use super::projectile::ProjectileType;
+use super::binary_helpers::{Binaryable, BinaryWriter, stable_hash};
+
+use serde::{Serialize, Deserialize};
+
+
+// Unit definitions the game loads at startup if the file exists in the
+// working directory. Without it the copy built into the binary is used.
+pub const DEFAULT_UNIT_FILE: &str = "units.json";
+
+const BUILT_IN_UNITS: &str = include_str!("../units.json");
+
+// Units with a longer attack distance than this shoot projectiles, the
+// others swing at their target
+pub const MELEE_REACH: f32 = 1.0;
+
+
+// Stats of one unit type. Distances are in tiles, speed in tiles per tick and
+// cooldown in ticks.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct UnitStats {
+    pub entity_type: EntityType,
+    pub hp: i32,
+    pub move_speed: f32,
+    pub seeing_distance: f32,
+    pub attack_distance: f32,
+    pub damage: u32,
+    pub cooldown: u32,
+    // Ticks from the start of a melee swing to the hit, less than the cooldown
+    #[serde(default)]
+    pub windup: u32,
+    // Projectiles also hit everyone this close to where they land. Without
+    // friendly fire they only hit units of other teams.
+    #[serde(default)]
+    pub splash_radius: f32,
+    #[serde(default)]
+    pub friendly_fire: bool,
+    #[serde(default)]
+    pub projectile_type: ProjectileType,
+    // Units closer than their radiuses combined push each other apart
+    pub collision_radius: f32,
+    // Row of the unit in the unit sprite sheets
+    pub sprite_row: u32,
+    #[serde(default)]
+    pub cost: HashMap<ResourceType, u32>,
+}
+
+
+impl UnitStats {
+    pub fn can_attack(&self) -> bool {
+        self.damage > 0
+    }
+
+    pub fn is_ranged(&self) -> bool {
+        self.can_attack() && self.attack_distance > MELEE_REACH
+    }
+}
+
+
+pub struct UnitRegistry {
+    units: HashMap<EntityType, UnitStats>,
+}
+
+
+static REGISTRY: OnceLock<UnitRegistry> = OnceLock::new();
+
+
+impl UnitRegistry {
+    pub fn from_json(text: &str) -> Result<UnitRegistry, String> {
+        let unit_list: Vec<UnitStats> = serde_json::from_str(text).map_err(|error| error.to_string())?;
+        let mut units: HashMap<EntityType, UnitStats> = HashMap::new();
+        for unit in unit_list {
+            if unit.hp <= 0 {
+                return Err(format!("{:?} must have positive hp", unit.entity_type));
+            }
+            if unit.windup >= unit.cooldown.max(1) {
+                return Err(format!("{:?} must have a windup shorter than its cooldown", unit.entity_type));
+            }
+            if unit.splash_radius < 0.0 {
+                return Err(format!("{:?} must not have a negative splash radius", unit.entity_type));
+            }
+            if units.contains_key(&unit.entity_type) {
+                return Err(format!("{:?} is defined twice", unit.entity_type));
+            }
+            units.insert(unit.entity_type.clone(), unit);
+        }
+        for entity_type in EntityType::all().iter() {
+            if !units.contains_key(entity_type) {
+                return Err(format!("{:?} is not defined", entity_type));
+            }
+        }
+        Ok(UnitRegistry { units: units })
+    }
+
+    pub fn built_in() -> UnitRegistry {
+        UnitRegistry::from_json(BUILT_IN_UNITS).expect("Built in unit definitions are valid")
+    }
+
+    pub fn stats(&self, entity_type: &EntityType) -> &UnitStats {
+        &self.units[entity_type]
+    }
+
+    pub fn simulation_hash(&self) -> u64 {
+        // Hash of the stats that change how a game plays out. Sprites and
+        // costs are left out as the simulation does not use them.
+        let mut writer = BinaryWriter::new();
+        for entity_type in EntityType::all().iter() {
+            let stats = self.stats(entity_type);
+            writer.write_i32(stats.hp);
+            writer.write_f32(stats.move_speed);
+            writer.write_f32(stats.seeing_distance);
+            writer.write_f32(stats.attack_distance);
+            writer.write_u32(stats.damage);
+            writer.write_u32(stats.cooldown);
+            writer.write_u32(stats.windup);
+            writer.write_f32(stats.splash_radius);
+            writer.write_u8(stats.friendly_fire as u8);
+            stats.projectile_type.write_binary(&mut writer);
+            writer.write_f32(stats.collision_radius);
+        }
+        stable_hash(&writer.into_binary())
+    }
+}
+
+
+pub fn load_file(file_name: &str) -> Result<(), String> {
+    // Must be called before the first stats() call, as the definitions can
+    // not change while a game is simulated
+    let text = std::fs::read_to_string(file_name).map_err(|error| format!("{}: {}", file_name, error))?;
+    let registry = UnitRegistry::from_json(&text).map_err(|error| format!("{}: {}", file_name, error))?;
+    REGISTRY.set(registry).map_err(|_| "Unit definitions are already loaded".to_string())
+}
+
+pub fn load_default_file() -> Result<(), String> {
+    if std::path::Path::new(DEFAULT_UNIT_FILE).exists() {
+        load_file(DEFAULT_UNIT_FILE)
+    } else {
+        Ok(())
+    }
+}
+
+pub fn stats(entity_type: &EntityType) -> &'static UnitStats {
+    REGISTRY.get_or_init(UnitRegistry::built_in).stats(entity_type)
+}
+
+pub fn simulation_hash() -> u64 {
+    REGISTRY.get_or_init(UnitRegistry::built_in).simulation_hash()
+}
+
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn built_in_units_match_the_original_stats() {
+        let registry = UnitRegistry::built_in();
+        for entity_type in EntityType::all().iter() {
+            let stats = registry.stats(entity_type);
+            assert_eq!(stats.hp, 200);
+            assert_eq!(stats.move_speed, 0.04);
+            assert_eq!(stats.cooldown, 45);
+            assert_eq!(stats.collision_radius * 2.0, 0.55);
+        }
+        let peasant = registry.stats(&EntityType::Peasant);
+        assert_eq!((peasant.attack_distance, peasant.damage), (0.0, 0));
+        let ranged = registry.stats(&EntityType::Ranged);
+        assert_eq!((ranged.attack_distance, ranged.damage, ranged.windup), (8.0, 12, 0));
+        assert_eq!((ranged.splash_radius, ranged.friendly_fire), (0.0, false));
+        assert_eq!(ranged.projectile_type, ProjectileType::Arrow);
+        let meelee = registry.stats(&EntityType::Meelee);
+        assert_eq!((meelee.attack_distance, meelee.damage, meelee.windup), (0.6, 10, 15));
+        assert_eq!(registry.stats(&EntityType::Ranged).cost[&ResourceType::Wood], 50);
+    }
+
+    #[test]
+    fn attacks_follow_damage_and_reach() {
+        let registry = UnitRegistry::built_in();
+        let peasant = registry.stats(&EntityType::Peasant);
+        assert!(!peasant.can_attack() && !peasant.is_ranged());
+        let meelee = registry.stats(&EntityType::Meelee);
+        assert!(meelee.can_attack() && !meelee.is_ranged());
+        let ranged = registry.stats(&EntityType::Ranged);
+        assert!(ranged.can_attack() && ranged.is_ranged());
+
+        let armed_peasant = UnitStats { damage: 5, ..peasant.clone() };
+        assert!(armed_peasant.can_attack() && !armed_peasant.is_ranged());
+        let spear_thrower = UnitStats { attack_distance: 3.0, ..meelee.clone() };
+        assert!(spear_thrower.is_ranged());
+    }
+
+    #[test]
+    fn simulation_hash_follows_simulated_stats() {
+        let registry = UnitRegistry::built_in();
+        assert_eq!(registry.simulation_hash(), UnitRegistry::built_in().simulation_hash());
+
+        let cheaper = BUILT_IN_UNITS.replace(r#""Gold": 40"#, r#""Gold": 30"#);
+        assert_eq!(UnitRegistry::from_json(&cheaper).unwrap().simulation_hash(), registry.simulation_hash());
+        let stronger = BUILT_IN_UNITS.replace(r#""damage": 12"#, r#""damage": 13"#);
+        assert_ne!(UnitRegistry::from_json(&stronger).unwrap().simulation_hash(), registry.simulation_hash());
+    }
+
+    #[test]
+    fn invalid_definitions_are_rejected() {
+        let ranged = r#"{"entity_type": "Ranged", "hp": 100, "move_speed": 0.05, "seeing_distance": 10.0,
+            "attack_distance": 6.0, "damage": 10, "cooldown": 30, "collision_radius": 0.3, "sprite_row": 2}"#;
+        let error = UnitRegistry::from_json(&format!("[{}]", ranged)).err().unwrap();
+        assert_eq!(error, "Peasant is not defined");
+        let error = UnitRegistry::from_json(&format!("[{}, {}]", ranged, ranged)).err().unwrap();
+        assert_eq!(error, "Ranged is defined twice");
+        assert!(UnitRegistry::from_json(r#"[{"entity_type": "Dragon"}]"#).is_err());
+        let slow_swing = ranged.replace(r#""cooldown": 30"#, r#""cooldown": 30, "windup": 30"#);
+        let error = UnitRegistry::from_json(&format!("[{}]", slow_swing)).err().unwrap();
+        assert_eq!(error, "Ranged must have a windup shorter than its cooldown");
+        let inverted_splash = ranged.replace(r#""damage": 10"#, r#""damage": 10, "splash_radius": -1.0"#);
+        let error = UnitRegistry::from_json(&format!("[{}]", inverted_splash)).err().unwrap();
+        assert_eq!(error, "Ranged must not have a negative splash radius");
+    }
+}
@@ -0,0 +1,96 @@
+// Helpers for building small scenarios and running them, shared by the
+// integration tests. Not every test file uses every helper.
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+use rust_game::point::Point;
+use rust_game::entity::{EntityType, Task};
+use rust_game::game_state::{GameState, GameEvent};
+
+
+// A map drawn as rows of ground tiles (see GroundType::as_char) plus units
+pub struct Scenario {
+    ground_layer: Vec<String>,
+    second_layer: Option<Vec<String>>,
+    units: Vec<Value>,
+}
+
+
+impl Scenario {
+    pub fn new(ground_layer: &[&str]) -> Scenario {
+        Scenario {
+            ground_layer: ground_layer.iter().map(|row| row.to_string()).collect(),
+            second_layer: None,
+            units: Vec::new(),
+        }
+    }
+
+    pub fn open_field(width: usize, height: usize) -> Scenario {
+        let row = ".".repeat(width);
+        let rows: Vec<&str> = (0..height).map(|_| row.as_str()).collect();
+        Scenario::new(&rows)
+    }
+
+    pub fn second_layer(mut self, second_layer: &[&str]) -> Scenario {
+        // Trees and buildings (see SecondLevelType::as_char)
+        self.second_layer = Some(second_layer.iter().map(|row| row.to_string()).collect());
+        self
+    }
+
+    pub fn unit(mut self, x: f32, y: f32, team_id: u32, entity_type: EntityType) -> Scenario {
+        // Units get ids in the order they are added, starting from 0
+        let id = self.units.len();
+        self.units.push(json!({
+            "location": {"x": x, "y": y},
+            "id": id,
+            "team_id": team_id,
+            "entity_type": entity_type,
+        }));
+        self
+    }
+
+    pub fn build(&self) -> GameState {
+        let mut map = json!({"ground_layer": self.ground_layer});
+        if let Some(second_layer) = &self.second_layer {
+            map["second_layer"] = json!(second_layer);
+        }
+        let scenario = json!({"map": map, "entity_holder": {"entities": self.units}});
+        GameState::from_json(&scenario.to_string()).expect("Scenario should be a valid game state")
+    }
+}
+
+
+pub fn order_units(game_state: &mut GameState, unit_ids: &[u32], task: Task) {
+    let unit_ids: HashMap<u32, bool> = unit_ids.iter().map(|id| (*id, true)).collect();
+    game_state.dispatch_event(GameEvent::OrderUnits { task: task, unit_ids: unit_ids });
+}
+
+pub fn move_task(x: f32, y: f32) -> Task {
+    Task::Move { point: Point::new(x, y) }
+}
+
+pub fn run_ticks(game_state: &mut GameState, ticks: u32) {
+    for _ in 0..ticks {
+        game_state.do_tick();
+    }
+}
+
+pub fn run_until<F: FnMut(&GameState) -> bool>(game_state: &mut GameState, max_ticks: u32, mut condition: F) -> bool {
+    // Runs until condition holds after a tick, returning false if it did not within max_ticks
+    for _ in 0..max_ticks {
+        game_state.do_tick();
+        if condition(game_state) {
+            return true;
+        }
+    }
+    false
+}
+
+pub fn team_size(game_state: &GameState, team_id: u32) -> usize {
+    game_state.entity_holder().entities_iter()
+        .filter(|entity| entity.team_id() == team_id)
+        .count()
+}
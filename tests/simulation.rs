@@ -0,0 +1,188 @@
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use rust_game::entity::{EntityType, Task};
+use rust_game::game_state::GameState;
+use rust_game::unit_types;
+
+use common::{Scenario, order_units, move_task, run_ticks, run_until, team_size};
+
+
+fn assert_on_moveable_tiles(game_state: &GameState) {
+    let map = game_state.map();
+    for entity in game_state.entity_holder().entities_iter() {
+        assert!(
+            map.point_moveable(entity.location().as_i()),
+            "Unit #{} is on an unmoveable tile at {:?} on tick {}",
+            entity.id(), entity.location(), game_state.tick(),
+        );
+    }
+}
+
+#[test]
+fn units_reach_move_target() {
+    let mut game_state = Scenario::open_field(12, 6)
+        .unit(1.3, 1.6, 0, EntityType::Ranged)
+        .build();
+    order_units(&mut game_state, &[0], move_task(9.5, 4.5));
+
+    assert!(run_until(&mut game_state, 600, |game_state| {
+        let entity = &game_state.entity_holder().entities[&0];
+        match entity.task() { Task::Idle => true, _ => false }
+    }));
+    let location = game_state.entity_holder().entities[&0].location();
+    assert_eq!(location.as_f(), (9.5, 4.5));
+}
+
+#[test]
+fn units_path_around_water() {
+    // The only way to the other side is through the gap at the bottom
+    let mut game_state = Scenario::new(&[
+        "....~.....",
+        "....~.....",
+        "....~.....",
+        "....~.....",
+        "..........",
+    ])
+        .unit(1.3, 1.6, 0, EntityType::Ranged)
+        .unit(2.3, 0.6, 0, EntityType::Ranged)
+        .build();
+    order_units(&mut game_state, &[0, 1], move_task(8.5, 1.5));
+
+    let arrived = run_until(&mut game_state, 1200, |game_state| {
+        assert_on_moveable_tiles(game_state);
+        game_state.entity_holder().entities_iter().all(|entity| entity.location().x > 5.0)
+    });
+    assert!(arrived, "Units did not get past the water");
+}
+
+#[test]
+fn units_never_end_up_on_unmoveable_tiles() {
+    // A crowd pushed against water, rock and trees from both sides
+    let mut scenario = Scenario::new(&[
+        "..........~~..........",
+        "..........~~..........",
+        "......................",
+        "..........~~..........",
+        "..........~~..........",
+        "..........~~..........",
+    ])
+        .second_layer(&[
+            "......................",
+            "......................",
+            "..........tt..........",
+            "......................",
+            "......................",
+            "......................",
+        ]);
+    for n in 0..6 {
+        scenario = scenario
+            .unit(2.3 + n as f32 * 0.4, 2.6, 0, EntityType::Ranged)
+            .unit(19.7 - n as f32 * 0.4, 2.4, 1, EntityType::Ranged);
+    }
+    let mut game_state = scenario.build();
+    order_units(&mut game_state, &[0, 2, 4, 6, 8, 10], move_task(20.5, 2.5));
+    order_units(&mut game_state, &[1, 3, 5, 7, 9, 11], move_task(1.5, 2.5));
+
+    for _ in 0..600 {
+        game_state.do_tick();
+        assert_on_moveable_tiles(&game_state);
+    }
+}
+
+#[test]
+fn ranged_units_beat_as_many_melee_units() {
+    let mut scenario = Scenario::open_field(30, 12);
+    for n in 0..4 {
+        scenario = scenario
+            .unit(3.3, 3.6 + n as f32 * 1.3, 0, EntityType::Ranged)
+            .unit(12.7, 3.4 + n as f32 * 1.3, 1, EntityType::Meelee);
+    }
+    let mut game_state = scenario.build();
+
+    let finished = run_until(&mut game_state, 6000, |game_state| {
+        team_size(game_state, 0) == 0 || team_size(game_state, 1) == 0
+    });
+    assert!(finished, "Battle did not end");
+    assert!(team_size(&game_state, 0) > 0);
+    assert_eq!(team_size(&game_state, 1), 0);
+}
+
+#[test]
+fn melee_units_hit_directly_after_their_windup() {
+    let mut game_state = Scenario::open_field(10, 5)
+        .unit(3.3, 2.6, 0, EntityType::Meelee)
+        .unit(3.88, 2.6, 1, EntityType::Meelee)
+        .build();
+    let stats = unit_types::stats(&EntityType::Meelee);
+
+    let mut swing_started_on: Option<u32> = None;
+    let hit = run_until(&mut game_state, 200, |game_state| {
+        assert!(game_state.entity_holder().projectiles.is_empty(), "Melee units should not shoot");
+        let attacker = &game_state.entity_holder().entities[&0];
+        if swing_started_on.is_none() && attacker.cooldown() > 0 {
+            swing_started_on = Some(game_state.tick());
+        }
+        game_state.entity_holder().entities[&1].hp() < stats.hp
+    });
+    assert!(hit, "Nobody was hit");
+
+    assert_eq!(game_state.tick() - swing_started_on.unwrap(), stats.windup);
+    let target = &game_state.entity_holder().entities[&1];
+    assert_eq!(target.hp(), stats.hp - stats.damage as i32);
+}
+
+#[test]
+fn melee_swings_are_cancelled_when_the_target_gets_away() {
+    let mut game_state = Scenario::open_field(14, 5)
+        .unit(3.3, 2.6, 0, EntityType::Meelee)
+        .unit(3.88, 2.6, 1, EntityType::Meelee)
+        .build();
+    let stats = unit_types::stats(&EntityType::Meelee);
+
+    assert!(run_until(&mut game_state, 200, |game_state| {
+        game_state.entity_holder().entities[&0].swing_target_id() == Some(1)
+    }), "Nobody swung");
+    order_units(&mut game_state, &[1], move_task(12.5, 2.6));
+
+    // The target steps out of reach well before the windup is over
+    assert!(run_until(&mut game_state, stats.windup - 1, |game_state| {
+        game_state.entity_holder().entities[&0].swing_target_id().is_none()
+    }), "Swing was not cancelled");
+    let attacker = &game_state.entity_holder().entities[&0];
+    assert_eq!(attacker.cooldown(), 0, "Cooldown should be refunded");
+    run_ticks(&mut game_state, stats.windup);
+    assert_eq!(game_state.entity_holder().entities[&1].hp(), stats.hp);
+}
+
+#[test]
+fn projectiles_are_removed_when_they_land() {
+    let mut game_state = Scenario::open_field(20, 8)
+        .unit(3.3, 3.6, 0, EntityType::Ranged)
+        .unit(9.7, 3.4, 1, EntityType::Peasant)
+        .build();
+
+    assert!(run_until(&mut game_state, 300, |game_state| {
+        game_state.entity_holder().projectiles.len() > 0
+    }), "Nobody shot");
+    // Projectiles fly less than the cooldown between shots
+    assert!(run_until(&mut game_state, 45, |game_state| {
+        game_state.entity_holder().projectiles.is_empty()
+    }), "Projectile was not removed");
+}
+
+#[test]
+fn damaged_units_die_and_are_removed() {
+    let mut game_state = Scenario::open_field(20, 8)
+        .unit(3.3, 3.6, 0, EntityType::Ranged)
+        .unit(3.3, 4.6, 0, EntityType::Ranged)
+        .unit(9.7, 3.4, 1, EntityType::Ranged)
+        .build();
+
+    assert!(run_until(&mut game_state, 6000, |game_state| team_size(game_state, 1) == 0));
+    assert!(game_state.entity_holder().entities_iter().all(|entity| entity.alive()));
+    run_ticks(&mut game_state, 200);
+    assert!(game_state.entity_holder().projectiles.is_empty());
+}